@@ -1,4 +1,12 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::{env, fs};
+
 fn main() {
+    robot_description();
+    ota_public_key();
     linker_be_nice();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
@@ -50,3 +58,324 @@ fn linker_be_nice() {
         std::env::current_exe().unwrap().display()
     );
 }
+
+/// Default robot descriptions, `ROBOT_DESCRIPTION` points to another one
+const QUADRUPED_DESCRIPTION: &str = "robot.toml";
+const HEXAPOD_DESCRIPTION: &str = "robot-hexapod.toml";
+
+/// In the order of the `Leg` enum
+const LEG_NAMES: [&str; 6] = [
+    "front_left",
+    "bottom_left",
+    "front_right",
+    "bottom_right",
+    "middle_left",
+    "middle_right",
+];
+/// In the order of the `Joint` enum
+const JOINT_NAMES: [&str; 3] = ["femur", "tibia", "coxa"];
+/// In the order of the `AuxServo` enum
+const AUX_NAMES: [&str; 3] = ["head_pan", "head_tilt", "gripper"];
+/// Full travel of a servo in degrees, `SERVO_ANGLE_RANGE` in config.rs
+const SERVO_TRAVEL: f32 = 180.0;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Description {
+    links: Links,
+    body: Body,
+    stance: Stance,
+    legs: BTreeMap<String, LegDescription>,
+    aux: AuxDescription,
+}
+
+/// Lengths in mm
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Links {
+    femur: f32,
+    tibia: f32,
+    coxa: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Body {
+    /// Distance between the coxa of two neighbouring corner legs
+    side: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stance {
+    z_default: f32,
+    z_up: f32,
+    z_boot: f32,
+    x_default: f32,
+    x_offset: f32,
+    y_start: f32,
+    y_step: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegDescription {
+    mount: Mount,
+    femur: JointDescription,
+    tibia: JointDescription,
+    coxa: JointDescription,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mount {
+    x: f32,
+    y: f32,
+    /// Direction of the leg x axis in the body frame, degrees counterclockwise from the right
+    yaw: f32,
+    /// The leg y axis is clockwise from its x axis
+    #[serde(default)]
+    mirrored: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JointDescription {
+    #[serde(default)]
+    driver: usize,
+    channel: u8,
+    direction: i8,
+    /// Servo angle for a joint angle of 0°
+    zero: f32,
+    /// Servo angles the joint stays within, the full travel by default
+    #[serde(default)]
+    min: f32,
+    #[serde(default = "full_travel")]
+    max: f32,
+}
+
+fn full_travel() -> f32 {
+    SERVO_TRAVEL
+}
+
+/// Servos that are not part of a leg
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuxDescription {
+    head_pan: ChannelDescription,
+    head_tilt: ChannelDescription,
+    gripper: ChannelDescription,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelDescription {
+    #[serde(default)]
+    driver: usize,
+    channel: u8,
+}
+
+impl AuxDescription {
+    fn servo(&self, name: &str) -> &ChannelDescription {
+        match name {
+            "head_pan" => &self.head_pan,
+            "head_tilt" => &self.head_tilt,
+            _ => &self.gripper,
+        }
+    }
+}
+
+impl LegDescription {
+    fn joint(&self, name: &str) -> &JointDescription {
+        match name {
+            "femur" => &self.femur,
+            "tibia" => &self.tibia,
+            _ => &self.coxa,
+        }
+    }
+}
+
+fn robot_description() {
+    println!("cargo:rerun-if-env-changed=ROBOT_DESCRIPTION");
+    let hexapod = env::var_os("CARGO_FEATURE_HEXAPOD").is_some();
+    let path = match env::var("ROBOT_DESCRIPTION") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) if hexapod => PathBuf::from(HEXAPOD_DESCRIPTION),
+        Err(_) => PathBuf::from(QUADRUPED_DESCRIPTION),
+    };
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Fail reading the robot description {}: {e}", path.display()));
+    let desc: Description = toml::from_str(&text)
+        .unwrap_or_else(|e| panic!("Invalid robot description {}: {e}", path.display()));
+
+    let leg_count = if hexapod { 6 } else { 4 };
+    let legs = &LEG_NAMES[..leg_count];
+    for name in desc.legs.keys() {
+        if !legs.contains(&name.as_str()) {
+            panic!(
+                "Unexpected leg `{name}` in {}, expected {legs:?}",
+                path.display()
+            );
+        }
+    }
+
+    let mut out = String::new();
+    let mut max_driver = 0;
+    let mut used = Vec::new();
+
+    // The auxiliary servos first, a leg can't take their channels
+    let mut aux = String::new();
+    for name in AUX_NAMES {
+        let servo = desc.aux.servo(name);
+        if servo.channel > 15 {
+            panic!("aux {name}: channel {} out of 0..=15", servo.channel);
+        }
+        if used.contains(&(servo.driver, servo.channel)) {
+            panic!(
+                "aux {name}: channel {} of driver {} already used",
+                servo.channel, servo.driver
+            );
+        }
+        used.push((servo.driver, servo.channel));
+        max_driver = max_driver.max(servo.driver);
+        writeln!(
+            aux,
+            "    ServoChannel::new({}, Channel::C{}), // {name}",
+            servo.driver, servo.channel
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "// Generated by build.rs from {}", path.display()).unwrap();
+    writeln!(out, "pub const LENGTH_A: f32 = {:?};", desc.links.femur).unwrap();
+    writeln!(out, "pub const LENGTH_B: f32 = {:?};", desc.links.tibia).unwrap();
+    writeln!(out, "pub const LENGTH_C: f32 = {:?};", desc.links.coxa).unwrap();
+    writeln!(out, "pub const LENGTH_SIDE: f32 = {:?};", desc.body.side).unwrap();
+
+    let stance = &desc.stance;
+    writeln!(out, "pub const Z_DEFAULT: f32 = {:?};", stance.z_default).unwrap();
+    writeln!(out, "pub const Z_UP: f32 = {:?};", stance.z_up).unwrap();
+    writeln!(out, "pub const Z_BOOT: f32 = {:?};", stance.z_boot).unwrap();
+    writeln!(out, "pub const X_DEFAULT: f32 = {:?};", stance.x_default).unwrap();
+    writeln!(out, "pub const X_OFFSET: f32 = {:?};", stance.x_offset).unwrap();
+    writeln!(out, "pub const Y_START: f32 = {:?};", stance.y_start).unwrap();
+    writeln!(out, "pub const Y_STEP: f32 = {:?};", stance.y_step).unwrap();
+
+    let mut mounts = String::new();
+    let mut servos = String::new();
+    for &name in legs {
+        let leg = desc
+            .legs
+            .get(name)
+            .unwrap_or_else(|| panic!("Leg `{name}` missing from {}", path.display()));
+
+        let m = &leg.mount;
+        let (sin, cos) = m.yaw.to_radians().sin_cos();
+        let handedness = if m.mirrored { -1.0f32 } else { 1.0 };
+        writeln!(
+            mounts,
+            "    LegMount::new({:?}, {:?}, {:?}, {:?}, {:?}), // {name}",
+            m.x, m.y, cos, sin, handedness
+        )
+        .unwrap();
+
+        writeln!(servos, "    [ // {name}").unwrap();
+        for joint_name in JOINT_NAMES {
+            let joint = leg.joint(joint_name);
+            if joint.channel > 15 {
+                panic!(
+                    "{name} {joint_name}: channel {} out of 0..=15",
+                    joint.channel
+                );
+            }
+            if joint.direction != 1 && joint.direction != -1 {
+                panic!("{name} {joint_name}: direction must be 1 or -1");
+            }
+            if !(0.0 <= joint.min && joint.min < joint.max && joint.max <= SERVO_TRAVEL) {
+                panic!(
+                    "{name} {joint_name}: limits {}..{} out of 0..{SERVO_TRAVEL}",
+                    joint.min, joint.max
+                );
+            }
+            if used.contains(&(joint.driver, joint.channel)) {
+                panic!(
+                    "{name} {joint_name}: channel {} of driver {} already used",
+                    joint.channel, joint.driver
+                );
+            }
+            used.push((joint.driver, joint.channel));
+            max_driver = max_driver.max(joint.driver);
+
+            writeln!(
+                servos,
+                "        JointServo::new(ServoChannel::new({}, Channel::C{}), {:?}, {:?}, {:?}, {:?}),",
+                joint.driver,
+                joint.channel,
+                joint.direction as f32,
+                joint.zero,
+                joint.min,
+                joint.max
+            )
+            .unwrap();
+        }
+        writeln!(servos, "    ],").unwrap();
+    }
+
+    writeln!(
+        out,
+        "pub static LEG_MOUNTS: [LegMount; LEG_COUNT] = [\n{mounts}];"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pub static SERVO_MAP: [[JointServo; 3]; LEG_COUNT] = [\n{servos}];"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pub static AUX_SERVO_MAP: [ServoChannel; AUX_SERVO_COUNT] = [\n{aux}];"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "const DESCRIPTION_DRIVER_COUNT: usize = {};",
+        max_driver + 1
+    )
+    .unwrap();
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("robot_description.rs"), out).unwrap();
+}
+
+/// Ed25519 public key checking the signature of the OTA images, given as 64 hex digits in the
+/// `OTA_PUBLIC_KEY` environment variable. Without it the firmware refuses every update.
+fn ota_public_key() {
+    println!("cargo:rerun-if-env-changed=OTA_PUBLIC_KEY");
+    let key = match env::var("OTA_PUBLIC_KEY") {
+        Ok(hex) => {
+            let hex = hex.trim();
+            if hex.len() != 64 {
+                panic!(
+                    "OTA_PUBLIC_KEY must be 64 hex digits, got {} characters",
+                    hex.len()
+                );
+            }
+            let bytes: Vec<u8> = (0..32)
+                .map(|i| {
+                    u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                        .unwrap_or_else(|_| panic!("OTA_PUBLIC_KEY isn't hexadecimal: {hex}"))
+                })
+                .collect();
+            format!("Some({bytes:?})")
+        }
+        Err(_) => String::from("None"),
+    };
+
+    let out = format!(
+        "// Generated by build.rs from OTA_PUBLIC_KEY\npub const OTA_PUBLIC_KEY: Option<[u8; 32]> = {key};\n"
+    );
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("ota_key.rs"), out).unwrap();
+}
@@ -0,0 +1,292 @@
+//! Shared-secret authentication of the remote control clients.
+//!
+//! Once a secret is stored in the settings, a client proves it knows it with a
+//! challenge-response: the robot sends a random challenge in hex, the client answers with the
+//! HMAC-SHA256 of that text keyed by the secret, in hex as well. Until then the client is
+//! read-only, it may only ask for the status. Without a secret every client has full control.
+//!
+//! HTTP being stateless, an HTTP client trades a good answer for a token sent back in the
+//! `Authorization: Bearer <token>` header. The addresses of the authenticated clients are
+//! trusted for the same time, which lets their UDP joystick packets through.
+extern crate alloc;
+
+use crate::config::{AUTH_CHALLENGE_TTL_MS, AUTH_SLOTS, AUTH_TOKEN_TTL_MS, SECRET_SIZE};
+use crate::hex;
+use crate::robot::commands::TcpCommand;
+use crate::settings::{self, SettingsError};
+use alloc::string::String;
+use core::cell::RefCell;
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_time::{Duration, Instant};
+use esp_hal::rng::Rng;
+use heapless::Vec;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const CHALLENGE_SIZE: usize = 16;
+const TOKEN_SIZE: usize = 16;
+/// Length of a challenge once in hex
+pub const CHALLENGE_HEX_SIZE: usize = 2 * CHALLENGE_SIZE;
+
+pub type Secret = heapless::String<SECRET_SIZE>;
+
+/// Hardware random generator, set by [`init`]
+static RNG: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Rng>>> =
+    BlockingMutex::new(RefCell::new(None));
+/// `None` until read from the settings, then the secret if one is set
+static SECRET: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Option<Secret>>>> =
+    BlockingMutex::new(RefCell::new(None));
+/// Values remembered for a while, up to [`AUTH_SLOTS`]
+type Slots<T> = BlockingMutex<CriticalSectionRawMutex, RefCell<Vec<Expiring<T>, AUTH_SLOTS>>>;
+
+/// Challenges handed to HTTP clients, waiting for their answer
+static CHALLENGES: Slots<Challenge> = BlockingMutex::new(RefCell::new(Vec::new()));
+static TOKENS: Slots<[u8; TOKEN_SIZE]> = BlockingMutex::new(RefCell::new(Vec::new()));
+static TRUSTED: Slots<IpAddress> = BlockingMutex::new(RefCell::new(Vec::new()));
+
+/// Give the authentication its random generator, before any client connects
+pub fn init(rng: Rng) {
+    RNG.lock(|cell| cell.replace(Some(rng)));
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    RNG.lock(|cell| {
+        let mut rng = cell.borrow_mut();
+        let rng = rng.as_mut().expect("auth::init wasn't called");
+        for chunk in bytes.chunks_mut(4) {
+            let random = rng.random().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    });
+    bytes
+}
+
+/// The stored secret, `None` when authentication is disabled
+pub fn secret() -> Option<Secret> {
+    if let Some(secret) = SECRET.lock(|cell| cell.borrow().clone()) {
+        return secret;
+    }
+    let secret = settings::load().auth_secret;
+    SECRET.lock(|cell| cell.replace(Some(secret.clone())));
+    secret
+}
+
+/// Store a new secret, an empty one disables authentication. The sessions already
+/// authenticated stay so, the HTTP tokens are revoked.
+pub async fn set_secret(secret: &str) -> Result<(), SetSecretError> {
+    let secret = match secret.trim() {
+        "" => None,
+        secret => Some(Secret::try_from(secret).map_err(|_| SetSecretError::TooLong)?),
+    };
+    let stored = secret.clone();
+    settings::update(|s| s.auth_secret = stored)
+        .await
+        .map_err(SetSecretError::Settings)?;
+    SECRET.lock(|cell| cell.replace(Some(secret)));
+    TOKENS.lock(|tokens| tokens.borrow_mut().clear());
+    TRUSTED.lock(|trusted| trusted.borrow_mut().clear());
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SetSecretError {
+    TooLong,
+    Settings(SettingsError),
+}
+
+impl core::fmt::Display for SetSecretError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SetSecretError::TooLong => write!(f, "secret longer than {SECRET_SIZE} bytes"),
+            SetSecretError::Settings(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Commands open to the clients that didn't authenticate
+pub fn read_only(cmd: &TcpCommand) -> bool {
+    matches!(
+        cmd,
+        TcpCommand::Status
+            | TcpCommand::CloseConnection
+            | TcpCommand::ListMacros
+            | TcpCommand::ListAnimations
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge([u8; CHALLENGE_SIZE]);
+
+impl Challenge {
+    pub fn new() -> Self {
+        Self(random_bytes())
+    }
+
+    pub fn hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    /// `response` is the HMAC of the challenge text keyed by the secret, in hex
+    fn verify(&self, secret: &str, response: &str) -> bool {
+        let Some(response) = hex::decode::<32>(response.trim()) else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+            return false;
+        };
+        mac.update(self.hex().as_bytes());
+        mac.verify_slice(&response).is_ok()
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Authentication state of a connection (TCP, WebSocket or Bluetooth LE)
+pub struct Session {
+    challenge: Challenge,
+    authenticated: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            challenge: Challenge::new(),
+            authenticated: secret().is_none(),
+        }
+    }
+
+    /// Challenge to send when the client connects, `None` without a secret
+    pub fn challenge(&self) -> Option<String> {
+        (!self.authenticated).then(|| self.challenge.hex())
+    }
+
+    /// Check the answer to the challenge, trusting the client address if it is right
+    pub fn authenticate(&mut self, response: &str, client: Option<IpAddress>) -> bool {
+        let Some(secret) = secret() else {
+            return true;
+        };
+        if self.challenge.verify(&secret, response) {
+            self.authenticated = true;
+            if let Some(client) = client {
+                trust(client);
+            }
+        } else {
+            // a new challenge for the next attempt
+            self.challenge = Challenge::new();
+        }
+        self.authenticated
+    }
+
+    pub fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    pub fn allows(&self, cmd: &TcpCommand) -> bool {
+        self.authenticated || read_only(cmd)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value forgotten after some time
+#[derive(Debug, Clone, Copy)]
+struct Expiring<T> {
+    value: T,
+    expires: Instant,
+}
+
+/// Add `value` for `ttl_ms`, dropping the expired values, or the oldest one when full
+fn remember<T, const N: usize>(list: &mut Vec<Expiring<T>, N>, value: T, ttl_ms: u64) {
+    forget_expired(list);
+    if list.is_full() {
+        list.remove(0);
+    }
+    let _ = list.push(Expiring {
+        value,
+        expires: Instant::now() + Duration::from_millis(ttl_ms),
+    });
+}
+
+fn forget_expired<T, const N: usize>(list: &mut Vec<Expiring<T>, N>) {
+    let now = Instant::now();
+    list.retain(|entry| entry.expires > now);
+}
+
+/// A challenge for an HTTP client, answered with [`http_login`]
+pub fn http_challenge() -> String {
+    let challenge = Challenge::new();
+    CHALLENGES.lock(|challenges| {
+        remember(
+            &mut challenges.borrow_mut(),
+            challenge,
+            AUTH_CHALLENGE_TTL_MS,
+        )
+    });
+    challenge.hex()
+}
+
+/// A token if `response` answers a pending challenge, each challenge being answered once
+pub fn http_login(challenge: &str, response: &str, client: IpAddress) -> Option<String> {
+    let secret = secret()?;
+    let challenge = Challenge(hex::decode(challenge.trim())?);
+    let pending = CHALLENGES.lock(|challenges| {
+        let mut challenges = challenges.borrow_mut();
+        forget_expired(&mut challenges);
+        let pending = challenges.len();
+        challenges.retain(|entry| entry.value != challenge);
+        challenges.len() < pending
+    });
+    if !pending || !challenge.verify(&secret, response) {
+        return None;
+    }
+
+    let token: [u8; TOKEN_SIZE] = random_bytes();
+    TOKENS.lock(|tokens| remember(&mut tokens.borrow_mut(), token, AUTH_TOKEN_TTL_MS));
+    trust(client);
+    Some(hex::encode(&token))
+}
+
+/// Whether an HTTP request may control the robot, from its `Authorization` header
+pub fn http_authorized(authorization: Option<&str>) -> bool {
+    if secret().is_none() {
+        return true;
+    }
+    let Some(token) = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| hex::decode::<TOKEN_SIZE>(token.trim()))
+    else {
+        return false;
+    };
+    TOKENS.lock(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        forget_expired(&mut tokens);
+        tokens.iter().any(|entry| entry.value == token)
+    })
+}
+
+fn trust(client: IpAddress) {
+    TRUSTED.lock(|trusted| remember(&mut trusted.borrow_mut(), client, AUTH_TOKEN_TTL_MS));
+}
+
+/// Whether a client without a session (the UDP joystick) may control the robot: no secret is
+/// set or its address recently authenticated
+pub fn trusted(client: IpAddress) -> bool {
+    if secret().is_none() {
+        return true;
+    }
+    TRUSTED.lock(|trusted| {
+        let mut trusted = trusted.borrow_mut();
+        forget_expired(&mut trusted);
+        trusted.iter().any(|entry| entry.value == client)
+    })
+}
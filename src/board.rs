@@ -0,0 +1,84 @@
+//! Board configuration for each hardware revision.
+//!
+//! Gathers what changes from one build of the robot to another: the I2C pins and bus speed,
+//! the PCA9685 addresses and their oscillator frequency. The revision is selected with a cargo
+//! feature (`board-v1` by default).
+//!
+//! The PCA9685 prescale is derived here from [`PCA_FREQUENCY_HZ`] and the oscillator
+//! frequency, as is the period actually produced once the prescale has been rounded.
+use crate::config::PCA_FREQUENCY_HZ;
+use esp_hal::{i2c::master::Config, time::Rate};
+
+#[cfg(all(feature = "board-v1", feature = "board-breakout"))]
+compile_error!("Select a single board revision feature");
+#[cfg(not(any(feature = "board-v1", feature = "board-breakout")))]
+compile_error!("Select a board revision feature: `board-v1` or `board-breakout`");
+
+/// Original robot: PCA9685 with every address pad bridged, the auxiliary one without A0.
+#[cfg(feature = "board-v1")]
+mod revision {
+    /// [legs, auxiliary servos]
+    pub const PCA_ADDRESSES: [u8; 2] = [0x7f, 0x7e];
+    pub const I2C_FREQUENCY_KHZ: u32 = 100;
+    /// Nominal value, not calibrated yet. Shared by both drivers.
+    ///
+    /// To calibrate it, measure the frequency of a servo output with a scope or a logic
+    /// analyser while the legs are powered: the oscillator runs at that frequency times
+    /// `4096 × (PCA_PRESCALE + 1)`, 499_712 with the nominal value (e.g. 50.9 Hz measured gives
+    /// 25_435_341).
+    pub const PCA_OSC_FREQUENCY_HZ: u32 = 25_000_000;
+
+    /// `(sda, scl)`
+    macro_rules! i2c_pins {
+        ($p:ident) => {
+            ($p.GPIO21, $p.GPIO22)
+        };
+    }
+    pub(crate) use i2c_pins;
+}
+
+/// Stock PCA9685 breakouts, the auxiliary one with A0 bridged.
+#[cfg(feature = "board-breakout")]
+mod revision {
+    /// [legs, auxiliary servos]
+    pub const PCA_ADDRESSES: [u8; 2] = [0x40, 0x41];
+    pub const I2C_FREQUENCY_KHZ: u32 = 400;
+    /// Nominal value, measure the PWM output of your module to calibrate it
+    pub const PCA_OSC_FREQUENCY_HZ: u32 = 25_000_000;
+
+    /// `(sda, scl)`
+    macro_rules! i2c_pins {
+        ($p:ident) => {
+            ($p.GPIO21, $p.GPIO22)
+        };
+    }
+    pub(crate) use i2c_pins;
+}
+
+pub(crate) use revision::i2c_pins;
+pub use revision::{I2C_FREQUENCY_KHZ, PCA_ADDRESSES, PCA_OSC_FREQUENCY_HZ};
+
+/// Number of PCA9685 sharing the I2C bus
+pub const PCA_COUNT: usize = PCA_ADDRESSES.len();
+
+/// Number of oscillator ticks in a PWM period of the PCA9685
+const PCA_COUNTER_SIZE: u32 = 4096;
+
+/// prescale = round(osc / (4096 × frequency)) − 1
+pub const PCA_PRESCALE: u8 = {
+    let ticks_per_period = PCA_COUNTER_SIZE * PCA_FREQUENCY_HZ;
+    let prescale = (PCA_OSC_FREQUENCY_HZ + ticks_per_period / 2) / ticks_per_period - 1;
+    // Bounds of the PRE_SCALE register
+    assert!(prescale >= 3, "PCA_FREQUENCY_HZ too high");
+    assert!(prescale <= 255, "PCA_FREQUENCY_HZ too low");
+    prescale as u8
+};
+
+/// Period of the PWM produced with [`PCA_PRESCALE`], which is not exactly the requested one
+pub const PCA_PERIOD_US: f32 = (PCA_COUNTER_SIZE as f32 * (PCA_PRESCALE as f32 + 1.0))
+    / PCA_OSC_FREQUENCY_HZ as f32
+    * 1_000_000.0;
+
+pub fn i2c_config() -> Config {
+    Config::default().with_frequency(Rate::from_khz(I2C_FREQUENCY_KHZ))
+}
@@ -1,54 +1,339 @@
 //! Physical and movement configuration constants.
 //!
 //! Contains all robot geometry, servo, and gait timing constants, such as leg lengths,
-//! servo pulse ranges, and step durations.
+//! servo pulse ranges, and step durations. The geometry and the servo wiring of the legs come
+//! from the robot description (`robot.toml`) read by `build.rs`.
 //!
 //! Used throughout the firmware for calculations and hardware interfacing.
+use crate::board::PCA_COUNT;
+use crate::robot::auxiliary::AUX_SERVO_COUNT;
+use crate::robot::leg::LEG_COUNT;
 use micromath::F32Ext;
 use pwm_pca9685::Channel;
 
 pub const SERVOCMD_CHANNEL_SIZE: usize = 4;
+pub const AUXCMD_CHANNEL_SIZE: usize = 4;
 pub const TCPCMD_CHANNEL_SIZE: usize = 4;
 
+/// Time given to the servo task to complete a movement before the gait engine faults
+pub const MOVEMENT_TIMEOUT_MS: u64 = 10_000;
+/// Maximum number of tasks subscribed to the robot status
+pub const STATUS_RECEIVERS: usize = 6;
+
 pub const PORT: u16 = 1234;
-pub const RX_BUF_SIZE: usize = 128;
+pub const RX_BUF_SIZE: usize = 256;
 pub const TX_BUF_SIZE: usize = 128;
+/// Period of the Wi-Fi signal strength measurement
+pub const RSSI_PERIOD_MS: u64 = 2_000;
+/// Time given to the access point to accept the robot
+pub const WIFI_CONNECT_TIMEOUT_MS: u64 = 15_000;
+/// Delay between two attempts at joining the stored network
+pub const WIFI_RETRY_MS: u64 = 5_000;
+
+pub const HTTP_PORT: u16 = 80;
+/// Largest HTTP request (headers and body) and response
+pub const HTTP_BUF_SIZE: usize = 1024;
+/// Time given to a client to send its request
+pub const HTTP_TIMEOUT_MS: u64 = 5_000;
+/// Largest WebSocket frame received, headers included
+pub const WS_BUF_SIZE: usize = 256;
+/// Telemetry rate of a new WebSocket client, changed with `rate <hz>`
+pub const TELEMETRY_HZ: u32 = 5;
+pub const TELEMETRY_MAX_HZ: u32 = 20;
+/// A WebSocket client silent for this long is disconnected
+pub const WS_TIMEOUT_MS: u64 = 15_000;
+
+// --- Discovery ---
+/// Longest hostname, a single DNS label
+pub const HOSTNAME_SIZE: usize = 63;
+/// Time to live of the mDNS records
+pub const MDNS_TTL_S: u32 = 120;
+/// Delay between the two announcements of the mDNS records
+pub const MDNS_ANNOUNCE_MS: u64 = 1_000;
+pub const MDNS_BUF_SIZE: usize = 512;
+
+// --- MQTT ---
+pub const MQTT_PORT: u16 = 1883;
+/// Longest broker address, `host[:port]`
+pub const BROKER_SIZE: usize = 64;
+/// Largest MQTT packet sent or received
+pub const MQTT_BUF_SIZE: usize = 512;
+/// Keep alive announced to the broker, the heartbeats keep the connection busy within it
+pub const MQTT_KEEP_ALIVE_S: u16 = 30;
+pub const MQTT_HEARTBEAT_MS: u64 = 10_000;
+/// Period of the pose check, the state is published when it changed
+pub const MQTT_STATE_PERIOD_MS: u64 = 500;
+/// Time given to the broker to answer the connection and subscription
+pub const MQTT_TIMEOUT_MS: u64 = 5_000;
+/// Delay between two attempts at reaching the broker
+pub const MQTT_RETRY_MS: u64 = 5_000;
+
+// --- OTA ---
+pub const OTA_PORT: u16 = 3232;
+pub const OTA_BUF_SIZE: usize = 4096;
+/// A client silent for this long during an update is disconnected
+pub const OTA_TIMEOUT_MS: u64 = 10_000;
+/// Time given to an update to get the servo task alive before it is rolled back
+pub const OTA_TRIAL_MS: u64 = 30_000;
+/// The watchdog resets the robot if the update hangs during its trial
+pub const OTA_WATCHDOG_MS: u64 = OTA_TRIAL_MS + 10_000;
+
+// --- Authentication ---
+pub const SECRET_SIZE: usize = 64;
+/// HTTP challenges, tokens and trusted addresses kept at a time, the oldest is dropped
+pub const AUTH_SLOTS: usize = 4;
+/// Time given to an HTTP client to answer its challenge
+pub const AUTH_CHALLENGE_TTL_MS: u64 = 30_000;
+/// Lifetime of an HTTP token and of the trust in an authenticated address
+pub const AUTH_TOKEN_TTL_MS: u64 = 600_000;
+
+// --- Macros ---
+/// Macros stored in the settings, besides the built-in ones
+pub const MACRO_COUNT: usize = 12;
+pub const MACRO_NAME_SIZE: usize = 16;
+/// Length of the steps of a macro, as text
+pub const MACRO_SIZE: usize = 200;
+
+// --- Animations ---
+/// Animations stored in flash, a build time check makes sure they fit in the sectors left
+pub const ANIMATION_COUNT: usize = 8;
+pub const ANIMATION_NAME_SIZE: usize = 16;
+/// Keyframes of an animation, at most 20 for 8 hexapod animations to fit
+pub const KEYFRAME_COUNT: usize = 20;
+/// Longest move to a keyframe, well under [`MOVEMENT_TIMEOUT_MS`]
+pub const KEYFRAME_MAX_MS: u16 = 5_000;
+
+/// Period of the state notifications to a BLE client
+pub const BLE_STATE_PERIOD_MS: u64 = 1_000;
+
+// --- Emotes ---
+/// Time spent on the expressive pose of an emote
+pub const EMOTE_HOLD_MS: u64 = 800;
+/// Nose down tilt of a bow (rad)
+pub const BOW_PITCH: f32 = 0.2;
+/// Backward move of the body during a bow, keeping it over the rear feet (mm)
+pub const BOW_SHIFT: f32 = 10.0;
+/// How low the body goes in a push-up (mm)
+pub const PUSH_UP_DEPTH: f32 = 20.0;
+/// Sideways move (mm) and roll (rad) of the body in a shimmy
+pub const SHIMMY_SHIFT: f32 = 15.0;
+pub const SHIMMY_ROLL: f32 = 0.1;
+/// Height of the foot saying hello in its leg frame (mm). It stays below the hip: the femur
+/// servo reaches 180° before the foot gets level with it.
+pub const HELLO_Z: f32 = -25.0;
+/// Sideways swing of the foot saying hello (mm)
+pub const HELLO_SWING: f32 = 20.0;
+pub const HELLO_WAVES: u8 = 3;
+/// Height of a waving foot in its leg frame (mm), about as high as the femur servo lifts it
+/// before reaching 180°
+pub const WAVE_Z: f32 = -20.0;
+
+// --- Stability ---
+/// Distance kept between the centre of the body and the edges of the support polygon when a
+/// foot lifts (mm), see `kinematics::stability`
+pub const STABILITY_MARGIN: f32 = 15.0;
+/// Rise of a foot above the lowest one for it to count as off the ground (mm)
+pub const LIFT_THRESHOLD: f32 = 5.0;
+
+// --- Limits ---
+/// Closest a foot comes to its coxa along the leg x axis (mm), keeping the leg clear of the
+/// body, see `kinematics::limits`
+pub const FOOT_MIN_X: f32 = 20.0;
+/// Distance kept from the folded and the stretched leg, where the knee locks (mm)
+pub const REACH_MARGIN: f32 = 5.0;
+
+// --- Teleoperation ---
+pub const UDP_PORT: u16 = 1235;
+/// The robot stops and stands when the UDP joystick is silent for this long
+pub const UDP_DEADMAN_MS: u64 = 500;
+/// Velocities below this magnitude stop the robot
+pub const VELOCITY_DEADZONE: f32 = 0.1;
+/// Fraction of the configured speeds used at the smallest velocity
+pub const TELEOP_MIN_SPEED: f32 = 0.3;
 
 // --- Servo Configuration ---
 pub const SERVO_MIN_PULSE_US: f32 = 544.0;
 pub const SERVO_MAX_PULSE_US: f32 = 2400.0;
 pub const SERVO_ANGLE_RANGE: f32 = 180.0;
-pub const PCA_FREQUENCY_HZ: u32 = 50;
-pub const PCA_PERIOD_US: f32 = 1_000_000.0 / PCA_FREQUENCY_HZ as f32; // 20000 µs
+pub const PCA_FREQUENCY_HZ: u32 = 50; // see board.rs for the prescale and actual period
 pub const PRESCALE_REG_SIZE: f32 = 4096.0;
 
-//[femur, tibia, coxa]
-pub static SERVO_CHANNEL_MAP: [[Channel; 3]; 4] = [
-    [Channel::C15, Channel::C14, Channel::C13], // front left
-    [Channel::C12, Channel::C11, Channel::C10], // bottom left
-    [Channel::C0, Channel::C1, Channel::C2],    // front right
-    [Channel::C3, Channel::C4, Channel::C5],    // bottom right
-];
-
-// ROBOT SIZE
-pub const LENGTH_A: f32 = 55.0;
-pub const LENGTH_B: f32 = 77.5;
-pub const LENGTH_C: f32 = 27.5;
-pub const LENGTH_SIDE: f32 = 71.0;
-pub const Z_ABSOLUTE: f32 = -28.0;
-
-///CONST FOR MOVEMENT
-pub const Z_DEFAULT: f32 = -50.0;
-pub const Z_UP: f32 = -30.0;
-pub const Z_BOOT: f32 = Z_ABSOLUTE;
-pub const X_DEFAULT: f32 = 62.0;
-pub const X_OFFSET: f32 = 0.0;
-pub const Y_START: f32 = 0.0;
-pub const Y_STEP: f32 = 40.0;
+// --- I2C error handling ---
+/// Attempts at writing a leg before resetting the I2C controller
+pub const I2C_RETRIES: u8 = 3;
+/// Delay between two attempts at bringing back a missing leg servo driver
+pub const DRIVER_RETRY_MS: u64 = 2_000;
+
+/// A servo output: the PCA9685 it is wired to (index in `board::PCA_ADDRESSES`) and its channel
+#[derive(Debug, Clone, Copy)]
+pub struct ServoChannel {
+    pub driver: usize,
+    pub channel: Channel,
+}
+
+impl ServoChannel {
+    pub const fn new(driver: usize, channel: Channel) -> Self {
+        Self { driver, channel }
+    }
+}
+
+/// The servo driving a joint and how the joint angle maps to the servo angle
+#[derive(Debug, Clone, Copy)]
+pub struct JointServo {
+    pub servo: ServoChannel,
+    /// 1.0 or -1.0, depending on how the servo is mounted
+    pub direction: f32,
+    /// Servo angle for a joint angle of 0°
+    pub zero: f32,
+    /// Servo angles the joint stays within, short of its mechanical stops
+    pub min: f32,
+    pub max: f32,
+}
+
+impl JointServo {
+    pub const fn new(servo: ServoChannel, direction: f32, zero: f32, min: f32, max: f32) -> Self {
+        Self {
+            servo,
+            direction,
+            zero,
+            min,
+            max,
+        }
+    }
+
+    /// Servo angle matching a joint angle
+    pub fn servo_angle(&self, joint_angle: f32) -> f32 {
+        self.zero + self.direction * joint_angle
+    }
+
+    /// Whether the servo can be set to `servo_angle`
+    pub fn allows(&self, servo_angle: f32) -> bool {
+        (self.min..=self.max).contains(&servo_angle)
+    }
+
+    /// `servo_angle` brought back within the limits
+    pub fn clamp(&self, servo_angle: f32) -> f32 {
+        servo_angle.clamp(self.min, self.max)
+    }
+}
+
+/// Where a leg is attached on the body.
+///
+/// `x` and `y` locate the coxa in the body frame (x to the right, y forward, mm). The leg x axis
+/// points away from the body with the direction (`cos`, `sin`) of its yaw, its y axis is
+/// counterclockwise from it, or clockwise for a mirrored leg (`handedness` of -1.0).
+#[derive(Debug, Clone, Copy)]
+pub struct LegMount {
+    pub x: f32,
+    pub y: f32,
+    pub cos: f32,
+    pub sin: f32,
+    pub handedness: f32,
+}
+
+impl LegMount {
+    pub const fn new(x: f32, y: f32, cos: f32, sin: f32, handedness: f32) -> Self {
+        Self {
+            x,
+            y,
+            cos,
+            sin,
+            handedness,
+        }
+    }
+
+    /// Convert a foot position from the leg frame to the body frame
+    pub fn to_body(&self, x: f32, y: f32) -> (f32, f32) {
+        let y = y * self.handedness;
+        (
+            self.x + x * self.cos - y * self.sin,
+            self.y + x * self.sin + y * self.cos,
+        )
+    }
+
+    /// Convert a foot position from the body frame to the leg frame
+    pub fn to_leg(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = (x - self.x, y - self.y);
+        (
+            dx * self.cos + dy * self.sin,
+            (dy * self.cos - dx * self.sin) * self.handedness,
+        )
+    }
+}
+
+// Link lengths, stance, leg mounts, SERVO_MAP ([femur, tibia, coxa] of each leg) and
+// AUX_SERVO_MAP ([head pan, head tilt, gripper], indexed by AuxServo) generated by build.rs
+// from the robot description (robot.toml)
+include!(concat!(env!("OUT_DIR"), "/robot_description.rs"));
+
+const _: () = assert!(
+    DESCRIPTION_DRIVER_COUNT <= PCA_COUNT,
+    "The robot description uses a PCA9685 missing from board.rs"
+);
+
+/// Body travel of one tripod step
+#[cfg(feature = "hexapod")]
+pub const HEX_STRIDE: f32 = Y_STEP;
+/// Body rotation of one tripod turn step (rad)
+#[cfg(feature = "hexapod")]
+pub const HEX_TURN_ANGLE: f32 = 0.25;
 
 /// functions parameter
 pub const KEEP: f32 = 255.0;
 
+/// Speeds of the gaits that can be tuned at runtime, see `robot::state`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Speeds {
+    pub move_speed: f32,
+    pub speed_multiple: f32,
+    pub spot_turn_speed: f32,
+    pub leg_move_speed: f32,
+    pub body_move_speed: f32,
+    pub stand_seat_speed: f32,
+}
+
+impl Speeds {
+    pub const DEFAULT: Self = Self {
+        move_speed: 1.0,
+        speed_multiple: 0.75,
+        spot_turn_speed: 4.0,
+        leg_move_speed: 8.0,
+        body_move_speed: 3.0,
+        stand_seat_speed: 1.0,
+    };
+
+    /// Every speed with its name
+    pub fn fields(&self) -> [(&'static str, f32); 6] {
+        [
+            ("move_speed", self.move_speed),
+            ("speed_multiple", self.speed_multiple),
+            ("spot_turn_speed", self.spot_turn_speed),
+            ("leg_move_speed", self.leg_move_speed),
+            ("body_move_speed", self.body_move_speed),
+            ("stand_seat_speed", self.stand_seat_speed),
+        ]
+    }
+
+    /// The speed called `name`, if any
+    pub fn field_mut(&mut self, name: &str) -> Option<&mut f32> {
+        match name {
+            "move_speed" => Some(&mut self.move_speed),
+            "speed_multiple" => Some(&mut self.speed_multiple),
+            "spot_turn_speed" => Some(&mut self.spot_turn_speed),
+            "leg_move_speed" => Some(&mut self.leg_move_speed),
+            "body_move_speed" => Some(&mut self.body_move_speed),
+            "stand_seat_speed" => Some(&mut self.stand_seat_speed),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Speeds {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 /// Stores the constant that need runtime op like sqrt or cos and variable that will be dynamically
 /// use by the program like the speeds
 #[derive(Debug, Default)]
@@ -86,12 +371,14 @@ impl RobotConfig {
         let turn_x0 = turn_x1 - temp_b * temp_alpha.cos();
         let turn_y0 = temp_b * temp_alpha.sin() - turn_y1 - LENGTH_SIDE;
 
-        let move_speed = 1.0;
-        let speed_multiple = 0.75;
-        let spot_turn_speed = 4.0;
-        let leg_move_speed = 8.0;
-        let body_move_speed = 3.0;
-        let stand_seat_speed = 1.0;
+        let Speeds {
+            move_speed,
+            speed_multiple,
+            spot_turn_speed,
+            leg_move_speed,
+            body_move_speed,
+            stand_seat_speed,
+        } = Speeds::DEFAULT;
 
         Self {
             temp_a,
@@ -112,4 +399,24 @@ impl RobotConfig {
             stand_seat_speed,
         }
     }
+
+    pub fn speeds(&self) -> Speeds {
+        Speeds {
+            move_speed: self.move_speed,
+            speed_multiple: self.speed_multiple,
+            spot_turn_speed: self.spot_turn_speed,
+            leg_move_speed: self.leg_move_speed,
+            body_move_speed: self.body_move_speed,
+            stand_seat_speed: self.stand_seat_speed,
+        }
+    }
+
+    pub fn set_speeds(&mut self, speeds: Speeds) {
+        self.move_speed = speeds.move_speed;
+        self.speed_multiple = speeds.speed_multiple;
+        self.spot_turn_speed = speeds.spot_turn_speed;
+        self.leg_move_speed = speeds.leg_move_speed;
+        self.body_move_speed = speeds.body_move_speed;
+        self.stand_seat_speed = speeds.stand_seat_speed;
+    }
 }
@@ -0,0 +1,26 @@
+//! Hexadecimal text of binary values: digests, signatures and challenges.
+extern crate alloc;
+
+use alloc::string::String;
+use core::fmt::Write as _;
+
+/// Lowercase hex of `bytes`
+pub fn encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        let _ = write!(text, "{byte:02x}");
+    }
+    text
+}
+
+/// The `N` bytes written in `text`, `None` if it isn't exactly `2 * N` hex digits
+pub fn decode<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
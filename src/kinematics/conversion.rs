@@ -3,17 +3,16 @@
 //! Provides functions to convert between Cartesian coordinates and joint angles
 //! for each leg, as well as mapping joint angles to servo pulse widths.
 //!
-//! Used by the gait engine (held by the motion task) to plan and execute leg movements.
+//! Used by the gait engine (held by the motion task) to plan leg movements and by the servo
+//! task to drive the servos.
 use core::f32::consts::PI;
 use micromath::F32Ext;
 
+use crate::board::PCA_PERIOD_US;
 use crate::config::*;
 use crate::robot::{joint::Joint, leg::Leg};
-use esp_hal::{i2c::master::I2c, Async};
-use log::error;
-use pwm_pca9685::Pca9685;
 
-fn angle_to_ticks(angle: f32) -> u16 {
+pub fn angle_to_ticks(angle: f32) -> u16 {
     let pulse_width_range = SERVO_MAX_PULSE_US - SERVO_MIN_PULSE_US;
     let pulse_us = SERVO_MIN_PULSE_US + (angle / SERVO_ANGLE_RANGE) * pulse_width_range;
     let tick = (pulse_us / PCA_PERIOD_US) * PRESCALE_REG_SIZE;
@@ -21,38 +20,6 @@ fn angle_to_ticks(angle: f32) -> u16 {
     tick.round().clamp(0.0, PRESCALE_REG_SIZE - 1.0) as u16
 }
 
-async fn set_leg_angles(
-    pwm: &mut Pca9685<I2c<'static, Async>>,
-    leg: Leg,
-    alpha: f32,
-    beta: f32,
-    gamma: f32,
-) {
-    let femur_tick = angle_to_ticks(alpha);
-    let tibia_tick = angle_to_ticks(beta);
-    let coxa_tick = angle_to_ticks(gamma);
-
-    let channels = SERVO_CHANNEL_MAP[leg as usize];
-    if let Err(e) = pwm
-        .set_channel_on_off(channels[Joint::Femur as usize], 0, femur_tick)
-        .await
-    {
-        error!("{e}");
-    }
-    if let Err(e) = pwm
-        .set_channel_on_off(channels[Joint::Tibia as usize], 0, tibia_tick)
-        .await
-    {
-        error!("{e}");
-    }
-    if let Err(e) = pwm
-        .set_channel_on_off(channels[Joint::Coxa as usize], 0, coxa_tick)
-        .await
-    {
-        error!("{e}");
-    }
-}
-
 /// transform in place alpha beta and gamma using mathematical model
 pub fn cartesian_to_polar(x: f32, y: f32, z: f32) -> (f32, f32, f32) {
     let (mut alpha, mut beta, mut gamma);
@@ -81,35 +48,14 @@ pub fn cartesian_to_polar(x: f32, y: f32, z: f32) -> (f32, f32, f32) {
     (alpha, beta, gamma)
 }
 
-pub async fn polar_to_servo(
-    pwm: &mut Pca9685<I2c<'static, Async>>,
-    leg: Leg,
-    mut alpha: f32,
-    mut beta: f32,
-    mut gamma: f32,
-) {
-    match leg {
-        Leg::FrontLeft => {
-            alpha = 90.0 - alpha;
-            beta = beta;
-            gamma += 90.0;
-        }
-        Leg::BottomLeft => {
-            alpha += 90.0;
-            beta = 180.0 - beta;
-            gamma = 90.0 - gamma;
-        }
-        Leg::FrontRight => {
-            alpha += 90.0;
-            beta = 180.0 - beta;
-            gamma = 90.0 - gamma;
-        }
-        Leg::BottomRight => {
-            alpha = 90.0 - alpha;
-            beta = beta;
-            gamma += 90.0;
-        }
-    }
-
-    set_leg_angles(pwm, leg, alpha, beta, gamma).await;
+/// Map the joint angles of a leg to the angles of its servos, indexed by [`Joint`]
+///
+/// The direction and zero of each servo come from the robot description.
+pub fn polar_to_servo(leg: Leg, alpha: f32, beta: f32, gamma: f32) -> [f32; 3] {
+    let servos = &SERVO_MAP[leg as usize];
+    let mut angles = [0.0; 3];
+    angles[Joint::Femur as usize] = servos[Joint::Femur as usize].servo_angle(alpha);
+    angles[Joint::Tibia as usize] = servos[Joint::Tibia as usize].servo_angle(beta);
+    angles[Joint::Coxa as usize] = servos[Joint::Coxa as usize].servo_angle(gamma);
+    angles
 }
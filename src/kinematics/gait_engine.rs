@@ -3,24 +3,73 @@
 //! Implements the state machine and algorithms for coordinated leg movement,
 //! including tripod gait sequencing and trajectory interpolation.
 //!
+//! The creep gaits below are written for the quadruped. With the `hexapod` feature they are
+//! replaced by the tripod gaits of the `tripod` module, under the same names. The emotes of the
+//! `emotes` module work for both.
+//!
 //! Used by the motion task to generate step patterns and synchronize legs.
 use crate::config::*;
-use crate::robot::{commands::ServoCommand, leg::Leg};
+use crate::kinematics::limits::{self, LimitError};
+use crate::kinematics::stability;
+use crate::robot::state::update_gait_state;
+use crate::robot::status::update_status;
+use crate::robot::{
+    commands::{ServoCommand, Velocity},
+    leg::{Leg, Pose, LEG_COUNT},
+};
+use crate::tasks::servo_task::{ServoError, SERVO_POSITION, UPDATE_PERIOD_MS};
 use core::f32;
+use core::fmt::Display;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender, signal::Signal};
 use embassy_time::{with_timeout, Duration};
-use log::{debug, error, info};
+use log::{debug, error, warn};
 use micromath::F32Ext;
+use serde::Serialize;
+
+mod emotes;
+#[cfg(feature = "hexapod")]
+mod tripod;
+
+/// Notification from the servo task that the last movement completed, or why it couldn't
+pub static MOVEMENT_COMPLETED: Signal<CriticalSectionRawMutex, Result<(), ServoError>> =
+    Signal::new();
+
+/// Reasons for the gait engine to abort a movement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MotionError {
+    /// The servo task didn't complete the movement in time
+    Timeout,
+    /// The servo task couldn't drive the servos
+    Servo(ServoError),
+    /// A previous fault hasn't been cleared yet
+    Faulted,
+    /// A foot target is out of the workspace of its leg, nothing moved
+    Limit(LimitError),
+    /// The other feet can't hold the body with this leg raised, nothing moved
+    Unbalanced(Leg),
+}
 
-pub static MOVEMENT_COMPLETED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+impl Display for MotionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MotionError::Timeout => f.write_str("movement timed out"),
+            MotionError::Servo(e) => write!(f, "servo failure: {e}"),
+            MotionError::Faulted => f.write_str("robot is in fault state"),
+            MotionError::Limit(e) => write!(f, "target rejected: {e}"),
+            MotionError::Unbalanced(leg) => write!(f, "{leg} can't be raised, the body would fall"),
+        }
+    }
+}
 
 /// State machine that calculate movements and update its posisions and speed accordingly
 pub struct GaitEngine {
-    current_pos: [[f32; 3]; 4], // real time coordinates of the end of each leg
-    expected_pos: [[f32; 3]; 4], // expected coordinates
-    temp_speed: [[f32; 3]; 4],  // Speed to reach expected pos.
+    current_pos: Pose,  // real time coordinates of the end of each leg
+    expected_pos: Pose, // expected coordinates
+    temp_speed: Pose,   // Speed to reach expected pos.
     config: RobotConfig,
     servo_cmd_sender: Sender<'static, CriticalSectionRawMutex, ServoCommand, SERVOCMD_CHANNEL_SIZE>, //channel for ServoCommand
+    fault: Option<MotionError>,    // blocks motion until cleared
+    violation: Option<LimitError>, // rejects the next movement
 }
 
 impl GaitEngine {
@@ -32,9 +81,9 @@ impl GaitEngine {
             SERVOCMD_CHANNEL_SIZE,
         >,
     ) -> Self {
-        let current_pos = [[0.0; 3]; 4];
-        let expected_pos = [[0.0; 3]; 4];
-        let temp_speed = [[0.0; 3]; 4];
+        let current_pos = [[0.0; 3]; LEG_COUNT];
+        let expected_pos = [[0.0; 3]; LEG_COUNT];
+        let temp_speed = [[0.0; 3]; LEG_COUNT];
         let config = RobotConfig::new();
 
         Self {
@@ -43,6 +92,8 @@ impl GaitEngine {
             expected_pos,
             temp_speed,
             config,
+            fault: None,
+            violation: None,
         }
     }
 
@@ -50,8 +101,18 @@ impl GaitEngine {
         &self.config
     }
 
+    /// Pose reached by the last movement
+    pub fn pose(&self) -> &Pose {
+        &self.current_pos
+    }
+
+    /// Speeds used from the next movement on
+    pub fn set_speeds(&mut self, speeds: Speeds) {
+        self.config.set_speeds(speeds);
+    }
+
     /// Init position arrays with initial values
-    pub async fn init_positions(&mut self) {
+    pub async fn init_positions(&mut self) -> Result<(), MotionError> {
         let speed = self.config.move_speed;
         self.set_site(
             Leg::FrontLeft,
@@ -81,52 +142,191 @@ impl GaitEngine {
             Z_BOOT,
             speed,
         );
+        #[cfg(feature = "hexapod")]
+        for leg in [Leg::MiddleLeft, Leg::MiddleRight] {
+            self.set_site(leg, X_DEFAULT, Y_START, Z_BOOT, speed);
+        }
 
-        for leg in 0..4 {
+        for leg in 0..LEG_COUNT {
             for joint in 0..3 {
                 self.current_pos[leg][joint] = self.expected_pos[leg][joint];
             }
         }
-        self.send_cmd().await;
+        self.send_cmd().await
     }
 
-    /// Send the internal state of the gait engine to the servo task and update position (it
-    /// assumes the command always succeed) //TODO: should it fail? watchdog? clean state?
-    pub async fn send_cmd(&mut self) {
+    /// Send the internal state of the gait engine to the servo task and update position.
+    ///
+    /// A movement lifting a foot is preceded by a body shift when the feet left on the ground
+    /// wouldn't hold the body with [`STABILITY_MARGIN`].
+    ///
+    /// If the servo task reports a failure or doesn't complete the movement in time, the legs
+    /// are stopped where they are, the pose is resynced from the servo task and the engine
+    /// enters fault state: every following command fails until [`GaitEngine::clear_fault`] is
+    /// called. A movement with a target out of reach is rejected without a fault, the feet
+    /// stay where they are.
+    pub async fn send_cmd(&mut self) -> Result<(), MotionError> {
+        if self.fault.is_some() {
+            return Err(MotionError::Faulted);
+        }
+        self.keep_balance().await?;
+        self.move_legs().await
+    }
+
+    /// Move the body over the feet staying on the ground if they wouldn't hold it well enough
+    /// during the movement to come
+    async fn keep_balance(&mut self) -> Result<(), MotionError> {
+        let Some(planted) = stability::planted_during(&self.current_pos, &self.expected_pos) else {
+            return Ok(());
+        };
+        let polygon = stability::support_polygon(&self.current_pos, &planted);
+        let Some(shift) = stability::balance_shift(&polygon, STABILITY_MARGIN) else {
+            return Ok(());
+        };
+        debug!(
+            "[MOTION_TASK] body shifted by ({}, {}) to keep its balance",
+            shift.0, shift.1
+        );
+
+        let (target, speed) = (self.expected_pos, self.temp_speed);
+        let shifted = stability::shift_body(&self.current_pos, shift);
+        for (leg, [x, y, z]) in shifted.into_iter().enumerate() {
+            self.set_site(leg.into(), x, y, z, self.config.body_move_speed);
+        }
+        self.move_legs().await?;
+        // the targets stay where they were on the ground, under the moved body
+        self.expected_pos = stability::shift_body(&target, shift);
+        self.temp_speed = speed;
+        Ok(())
+    }
+
+    async fn move_legs(&mut self) -> Result<(), MotionError> {
+        if let Some(e) = self.violation.take() {
+            self.expected_pos = self.current_pos;
+            self.temp_speed = [[0.0; 3]; LEG_COUNT];
+            return Err(MotionError::Limit(e));
+        }
         let cmd = ServoCommand::new(self.current_pos, self.expected_pos, self.temp_speed);
+        // a completion left over from a preempted command must not be mistaken for this one
+        MOVEMENT_COMPLETED.reset();
         self.servo_cmd_sender.send(cmd).await;
 
         // wait for the notification from the servo task
-        match with_timeout(Duration::from_secs(10), MOVEMENT_COMPLETED.wait()).await {
-            Ok(_) => self.current_pos = self.expected_pos,
-            Err(_) => error!("[MOTION_TASK] command timed out"),
+        match with_timeout(
+            Duration::from_millis(MOVEMENT_TIMEOUT_MS),
+            MOVEMENT_COMPLETED.wait(),
+        )
+        .await
+        {
+            Ok(Ok(())) => {
+                self.current_pos = self.expected_pos;
+                self.publish_pose();
+                Ok(())
+            }
+            Ok(Err(e)) => self.enter_fault(MotionError::Servo(e)),
+            Err(_) => self.enter_fault(MotionError::Timeout),
         }
     }
 
-    pub async fn do_test(&mut self) {
-        info!("Stand");
-        self.stand().await;
-        embassy_time::Timer::after_secs(2).await;
-        info!("Wave");
-        self.wave(2).await;
-        embassy_time::Timer::after_secs(2).await;
-        info!("Step forward");
-        self.step_forward(2).await;
-        embassy_time::Timer::after_secs(2).await;
-        info!("Sit");
-        self.sit().await;
-        embassy_time::Timer::after_secs(5).await;
+    /// Stop the legs where they are and block motion until the fault is cleared
+    fn enter_fault(&mut self, fault: MotionError) -> Result<(), MotionError> {
+        error!("[MOTION_TASK] {fault}, entering fault state");
+        self.resync();
+        // Stop the legs where they are instead of letting them finish a movement the
+        // engine gave up on
+        let hold = ServoCommand::new(self.current_pos, self.current_pos, [[0.0; 3]; LEG_COUNT]);
+        if self.servo_cmd_sender.try_send(hold).is_err() {
+            warn!("[MOTION_TASK] servo queue full, couldn't stop the legs");
+        }
+        self.fault = Some(fault);
+        update_status(|status| status.fault = Some(fault));
+        Err(fault)
+    }
+
+    /// The fault that blocks motion, if any
+    pub fn fault(&self) -> Option<MotionError> {
+        self.fault
+    }
+
+    /// Stop the legs where they are, giving up on a movement interrupted before its end, and
+    /// wait for the servo task to hold them
+    pub async fn halt(&mut self) -> Result<(), MotionError> {
+        self.resync();
+        self.move_legs().await
+    }
+
+    /// Resync the pose with the servo task and accept motion commands again
+    pub fn clear_fault(&mut self) {
+        self.resync();
+        self.fault = None;
+        update_status(|status| status.fault = None);
+    }
+
+    /// Align the pose of the engine with the position the servo task last reached, or keep its
+    /// own if the servos were never written
+    fn resync(&mut self) {
+        if let Some(pos) = SERVO_POSITION.lock(|pos| pos.get()) {
+            self.current_pos = pos;
+        }
+        self.expected_pos = self.current_pos;
+        self.temp_speed = [[0.0; 3]; LEG_COUNT];
+        self.violation = None;
+        self.publish_pose();
+    }
+
+    fn publish_pose(&self) {
+        let pose = self.current_pos;
+        update_gait_state(|state| state.pose = pose);
+    }
+
+    /// One gait cycle in the dominant direction of `velocity`
+    pub async fn teleop_step(&mut self, velocity: Velocity) -> Result<(), MotionError> {
+        if velocity.forward.abs() >= velocity.turn.abs() {
+            if velocity.forward > 0.0 {
+                self.step_forward(1).await
+            } else {
+                self.step_backward(1).await
+            }
+        } else if velocity.turn > 0.0 {
+            self.turn_left(1).await
+        } else {
+            self.turn_right(1).await
+        }
+    }
+
+    /// Move a single foot, in its leg frame
+    pub async fn set_foot(&mut self, leg: Leg, x: f32, y: f32, z: f32) -> Result<(), MotionError> {
+        self.set_site(leg, x, y, z, self.config.leg_move_speed);
+        self.send_cmd().await
+    }
+
+    /// Move every foot in a straight line to `pose`, all of them arriving after `duration_ms`.
+    ///
+    /// The speeds don't apply, each foot goes as fast as its distance to cover requires.
+    pub async fn move_to(&mut self, pose: &Pose, duration_ms: u32) -> Result<(), MotionError> {
+        for (leg, foot) in pose.iter().enumerate() {
+            self.accept(leg.into(), *foot);
+        }
+        let ticks = (duration_ms as u64 / UPDATE_PERIOD_MS).max(1) as f32;
+        for leg in 0..LEG_COUNT {
+            for axis in 0..3 {
+                self.temp_speed[leg][axis] =
+                    (pose[leg][axis] - self.current_pos[leg][axis]) / ticks;
+            }
+        }
+        self.expected_pos = *pose;
+        self.send_cmd().await
     }
 
-    pub async fn sit(&mut self) {
-        for leg in 0..4 {
+    pub async fn sit(&mut self) -> Result<(), MotionError> {
+        for leg in 0..LEG_COUNT {
             self.set_site(leg.into(), KEEP, KEEP, Z_BOOT, self.config.stand_seat_speed);
         }
-        self.send_cmd().await;
+        self.send_cmd().await
     }
 
-    pub async fn stand(&mut self) {
-        for leg in 0..4 {
+    pub async fn stand(&mut self) -> Result<(), MotionError> {
+        for leg in 0..LEG_COUNT {
             self.set_site(
                 leg.into(),
                 KEEP,
@@ -135,16 +335,17 @@ impl GaitEngine {
                 self.config.stand_seat_speed,
             );
         }
-        self.send_cmd().await;
+        self.send_cmd().await
     }
 
-    pub async fn step_forward(&mut self, times: u8) {
+    #[cfg(not(feature = "hexapod"))]
+    pub async fn step_forward(&mut self, times: u8) -> Result<(), MotionError> {
         let mut speed = self.config.leg_move_speed;
 
         for _ in 0..times {
             if self.current_pos[Leg::FrontRight][1] == Y_START {
                 self.set_site(Leg::FrontRight, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontRight,
                     X_DEFAULT + X_OFFSET,
@@ -152,7 +353,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontRight,
                     X_DEFAULT + X_OFFSET,
@@ -160,7 +361,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 speed = self.config.body_move_speed;
                 self.set_site(
@@ -191,7 +392,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 speed = self.config.leg_move_speed;
                 self.set_site(
@@ -201,9 +402,9 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(Leg::BottomLeft, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::BottomLeft,
                     X_DEFAULT + X_OFFSET,
@@ -211,10 +412,10 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             } else {
                 self.set_site(Leg::FrontLeft, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontLeft,
                     X_DEFAULT + X_OFFSET,
@@ -222,7 +423,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontLeft,
                     X_DEFAULT + X_OFFSET,
@@ -230,7 +431,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 speed = self.config.body_move_speed;
                 self.set_site(
@@ -261,7 +462,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 speed = self.config.leg_move_speed;
                 self.set_site(
@@ -271,9 +472,9 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(Leg::BottomRight, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::BottomRight,
                     X_DEFAULT + X_OFFSET,
@@ -281,12 +482,14 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             }
         }
+        Ok(())
     }
 
-    pub async fn step_backward(&mut self, times: u8) {
+    #[cfg(not(feature = "hexapod"))]
+    pub async fn step_backward(&mut self, times: u8) -> Result<(), MotionError> {
         let leg_speed = self.config.leg_move_speed;
         let body_speed = self.config.body_move_speed;
 
@@ -300,7 +503,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::BottomRight,
                     X_DEFAULT + X_OFFSET,
@@ -308,7 +511,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomRight,
@@ -317,7 +520,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 // Body shift
                 self.set_site(
@@ -348,7 +551,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     body_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 // Move other leg
                 self.set_site(
@@ -358,7 +561,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontLeft,
                     X_DEFAULT + X_OFFSET,
@@ -366,7 +569,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontLeft,
                     X_DEFAULT + X_OFFSET,
@@ -374,7 +577,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             } else {
                 // Leg 1 (BottomLeft) & 2 (FrontRight) move
                 self.set_site(
@@ -384,7 +587,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::BottomLeft,
                     X_DEFAULT + X_OFFSET,
@@ -392,7 +595,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::BottomLeft,
                     X_DEFAULT + X_OFFSET,
@@ -400,7 +603,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 // Body shift
                 self.set_site(
@@ -432,7 +635,7 @@ impl GaitEngine {
                     body_speed,
                 );
 
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 // Move other leg
                 self.set_site(
@@ -442,7 +645,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontRight,
                     X_DEFAULT + X_OFFSET,
@@ -450,7 +653,7 @@ impl GaitEngine {
                     Z_UP,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
                 self.set_site(
                     Leg::FrontRight,
                     X_DEFAULT + X_OFFSET,
@@ -458,19 +661,21 @@ impl GaitEngine {
                     Z_DEFAULT,
                     leg_speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             }
         }
+        Ok(())
     }
 
-    pub async fn turn_left(&mut self, times: u8) {
+    #[cfg(not(feature = "hexapod"))]
+    pub async fn turn_left(&mut self, times: u8) -> Result<(), MotionError> {
         let speed = self.config.spot_turn_speed;
 
         for _ in 0..times {
             if self.current_pos[Leg::BottomRight][1] == Y_START {
                 // Leg 3 & 1 move
                 self.set_site(Leg::BottomRight, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -500,7 +705,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomRight,
@@ -509,7 +714,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -539,7 +744,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomLeft,
@@ -548,7 +753,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -572,7 +777,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomLeft,
@@ -581,11 +786,11 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             } else {
                 // Leg 0 & 2 move
                 self.set_site(Leg::FrontLeft, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -615,7 +820,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -624,7 +829,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -654,7 +859,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontRight,
@@ -663,7 +868,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -687,7 +892,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontRight,
@@ -696,19 +901,21 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             }
         }
+        Ok(())
     }
 
-    pub async fn turn_right(&mut self, times: u8) {
+    #[cfg(not(feature = "hexapod"))]
+    pub async fn turn_right(&mut self, times: u8) -> Result<(), MotionError> {
         let speed = self.config.spot_turn_speed;
 
         for _ in 0..times {
             if self.current_pos[Leg::FrontRight][1] == Y_START {
                 // Leg 2 (FrontRight) & 0 (FrontLeft) move
                 self.set_site(Leg::FrontRight, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -738,7 +945,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontRight,
@@ -747,7 +954,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -777,7 +984,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -786,7 +993,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(Leg::FrontLeft, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
                 self.set_site(
@@ -810,7 +1017,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -819,11 +1026,11 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             } else {
                 // Leg 1 (BottomLeft) & 3 (BottomRight) move
                 self.set_site(Leg::BottomLeft, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -853,7 +1060,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomLeft,
@@ -862,7 +1069,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -892,7 +1099,7 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomRight,
@@ -901,7 +1108,7 @@ impl GaitEngine {
                     Z_UP,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::FrontLeft,
@@ -925,7 +1132,7 @@ impl GaitEngine {
                     speed,
                 );
                 self.set_site(Leg::BottomRight, X_DEFAULT + X_OFFSET, Y_START, Z_UP, speed);
-                self.send_cmd().await;
+                self.send_cmd().await?;
 
                 self.set_site(
                     Leg::BottomRight,
@@ -934,58 +1141,24 @@ impl GaitEngine {
                     Z_DEFAULT,
                     speed,
                 );
-                self.send_cmd().await;
+                self.send_cmd().await?;
             }
         }
+        Ok(())
     }
 
-    /// Move front right leg or front left depending on?
-    pub async fn wave(&mut self, times: u8) {
-        let (x_tmp, y_tmp, z_tmp);
-        let leg;
-        let speed = self.config.body_move_speed;
-
-        if self.current_pos[3][1] == Y_START {
-            leg = Leg::FrontRight;
-            self.body_right(15).await;
-            x_tmp = self.current_pos[leg][0];
-            y_tmp = self.current_pos[leg][1];
-            z_tmp = self.current_pos[leg][2];
-
-            for _ in 0..times {
-                self.set_site(leg, self.config.turn_x1, self.config.turn_y1, 50.0, speed);
-                self.send_cmd().await;
-                self.set_site(leg, self.config.turn_x0, self.config.turn_y0, 50.0, speed);
-                self.send_cmd().await;
-            }
-            self.set_site(leg, x_tmp, y_tmp, z_tmp, speed);
-            self.send_cmd().await;
-            self.body_left(15).await;
-        } else {
-            leg = Leg::FrontLeft;
-            self.body_left(15).await;
-            x_tmp = self.current_pos[leg][0];
-            y_tmp = self.current_pos[leg][1];
-            z_tmp = self.current_pos[leg][2];
-            self.config.move_speed = self.config.body_move_speed; //update the move speed before
-                                                                  //setting sites
-
-            for _ in 0..times {
-                self.set_site(leg, self.config.turn_x1, self.config.turn_y1, 50.0, speed);
-                self.send_cmd().await;
-                self.set_site(leg, self.config.turn_x0, self.config.turn_y0, 50.0, speed);
-                self.send_cmd().await;
+    /// Update expected site and temp_speed, unless the leg can't reach the site
+    fn set_site(&mut self, leg: Leg, x: f32, y: f32, z: f32, move_speed: f32) {
+        let mut target = self.expected_pos[leg];
+        for (coordinate, value) in target.iter_mut().zip([x, y, z]) {
+            if value != KEEP {
+                *coordinate = value;
             }
-            self.set_site(leg, x_tmp, y_tmp, z_tmp, speed);
-            self.send_cmd().await;
-            self.config.move_speed = 1.0;
-            self.body_right(15).await;
         }
-        debug!("[MOTION TASK] wave completed!")
-    }
+        if !self.accept(leg, target) {
+            return;
+        }
 
-    /// Update expected site and temp_speed
-    fn set_site(&mut self, leg: Leg, x: f32, y: f32, z: f32, move_speed: f32) {
         let (mut length_x, mut length_y, mut length_z) = (0.0, 0.0, 0.0);
 
         if x != KEEP {
@@ -1016,70 +1189,16 @@ impl GaitEngine {
         }
     }
 
-    async fn body_left(&mut self, i: i32) {
-        let speed = self.config.move_speed;
-        self.set_site(
-            Leg::FrontLeft,
-            self.current_pos[0][0] + i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::BottomLeft,
-            self.current_pos[1][0] + i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::FrontRight,
-            self.current_pos[2][0] - i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::BottomRight,
-            self.current_pos[3][0] - i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.send_cmd().await;
-    }
-
-    async fn body_right(&mut self, i: i32) {
-        let speed = self.config.move_speed;
-        self.set_site(
-            Leg::FrontLeft,
-            self.current_pos[0][0] - i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::BottomLeft,
-            self.current_pos[1][0] - i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::FrontRight,
-            self.current_pos[2][0] + i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.set_site(
-            Leg::BottomRight,
-            self.current_pos[3][0] + i as f32,
-            KEEP,
-            KEEP,
-            speed,
-        );
-        self.send_cmd().await;
+    /// Whether `leg` can reach `target`, otherwise the next movement is rejected
+    fn accept(&mut self, leg: Leg, target: [f32; 3]) -> bool {
+        match limits::check(leg, target) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("[MOTION_TASK] {e}");
+                self.violation.get_or_insert(e);
+                false
+            }
+        }
     }
 }
 
@@ -1089,6 +1208,8 @@ impl core::fmt::Debug for GaitEngine {
             .field("current_pos", &self.current_pos)
             .field("expected_pos", &self.expected_pos)
             .field("temp_speed", &self.temp_speed)
+            .field("fault", &self.fault)
+            .field("violation", &self.violation)
             .finish()
     }
 }
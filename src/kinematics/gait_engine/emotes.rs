@@ -0,0 +1,284 @@
+//! Expressive motions, built on body poses.
+//!
+//! A body pose moves the body over feet that stay on the ground: shifted, tilted and turned
+//! from the stance the emote started on. Foot targets are computed in the body frame from
+//! [`LEG_MOUNTS`], so the emotes work for the quadruped and the hexapod alike. The tilts are
+//! small, they only raise or lower the feet.
+//!
+//! Before raising a leg, the body moves over the other feet until they hold it with
+//! [`STABILITY_MARGIN`]. Every emote ends back on the stance it started on, also when one of
+//! its steps fails, unless the engine entered fault state.
+use super::{GaitEngine, MotionError};
+use crate::config::*;
+use crate::kinematics::stability;
+use crate::robot::leg::{Leg, Pose, LEG_COUNT};
+use embassy_time::Timer;
+use log::warn;
+use micromath::F32Ext;
+
+/// Position and orientation of the body relative to a stance, in mm and rad
+#[derive(Debug, Clone, Copy)]
+struct BodyPose {
+    /// Right, forward and up
+    shift: [f32; 3],
+    /// Nose down
+    pitch: f32,
+    /// Right side down
+    roll: f32,
+    /// To the left
+    yaw: f32,
+}
+
+impl BodyPose {
+    const NEUTRAL: Self = Self {
+        shift: [0.0; 3],
+        pitch: 0.0,
+        roll: 0.0,
+        yaw: 0.0,
+    };
+}
+
+/// Body poses of the dance, each one reached at the body speed
+const DANCE: [BodyPose; 10] = [
+    BodyPose {
+        shift: [SHIMMY_SHIFT, 0.0, 0.0],
+        roll: SHIMMY_ROLL,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [-SHIMMY_SHIFT, 0.0, 0.0],
+        roll: -SHIMMY_ROLL,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [SHIMMY_SHIFT, 0.0, 0.0],
+        roll: SHIMMY_ROLL,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [-SHIMMY_SHIFT, 0.0, 0.0],
+        roll: -SHIMMY_ROLL,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        yaw: 0.15,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        yaw: -0.15,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [0.0, 0.0, -PUSH_UP_DEPTH],
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [0.0, 0.0, PUSH_UP_DEPTH / 2.0],
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [0.0, -BOW_SHIFT, 0.0],
+        pitch: BOW_PITCH,
+        ..BodyPose::NEUTRAL
+    },
+    BodyPose {
+        shift: [0.0, BOW_SHIFT, 0.0],
+        pitch: -BOW_PITCH,
+        ..BodyPose::NEUTRAL
+    },
+];
+
+/// Feet of `stance`, in their leg frames, once the body is moved to `pose`
+fn feet_under(stance: &Pose, pose: &BodyPose) -> Pose {
+    let mut feet = *stance;
+    // The feet move the opposite way of the body
+    let (sin, cos) = (-pose.yaw).sin_cos();
+    for (foot, mount) in feet.iter_mut().zip(&LEG_MOUNTS) {
+        let (bx, by) = mount.to_body(foot[0], foot[1]);
+        let (rx, ry) = (bx * cos - by * sin, bx * sin + by * cos);
+        let (x, y) = mount.to_leg(rx - pose.shift[0], ry - pose.shift[1]);
+        let z = foot[2] - pose.shift[2] + by * pose.pitch.tan() + bx * pose.roll.tan();
+        *foot = [x, y, z];
+    }
+    feet
+}
+
+/// Body pose over the feet of `stance` but the one of `raised`, for them to hold it. Fails if
+/// they can't, their polygon having no area.
+fn over_others(stance: &Pose, raised: Leg) -> Result<BodyPose, MotionError> {
+    let mut planted = [true; LEG_COUNT];
+    planted[raised as usize] = false;
+    let polygon = stability::support_polygon(stance, &planted);
+    if stability::margin(&polygon, (0.0, 0.0)).is_none() {
+        return Err(MotionError::Unbalanced(raised));
+    }
+    // no shift when the body is stable enough already
+    let (x, y) = stability::balance_shift(&polygon, STABILITY_MARGIN).unwrap_or((0.0, 0.0));
+    Ok(BodyPose {
+        shift: [x, y, 0.0],
+        ..BodyPose::NEUTRAL
+    })
+}
+
+impl GaitEngine {
+    /// Raise `leg` and wave it `times` times
+    pub async fn wave(&mut self, leg: Leg, times: u8) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        if let Err(e) = self.body_pose(&stance, &over_others(&stance, leg)?).await {
+            return self.end_at(&stance, Err(e)).await;
+        }
+
+        let speed = self.config.body_move_speed;
+        let [x_tmp, y_tmp, z_tmp] = self.current_pos[leg];
+        let waved = async {
+            for _ in 0..times {
+                self.set_site(leg, self.config.turn_x1, self.config.turn_y1, WAVE_Z, speed);
+                self.send_cmd().await?;
+                self.set_site(leg, self.config.turn_x0, self.config.turn_y0, WAVE_Z, speed);
+                self.send_cmd().await?;
+            }
+            Ok(())
+        }
+        .await;
+        // the leg comes back down even after a failed wave
+        self.set_site(leg, x_tmp, y_tmp, z_tmp, speed);
+        let lowered = self.send_cmd().await;
+        self.end_at(&stance, waved.and(lowered)).await
+    }
+
+    /// Bend forward, then back up
+    pub async fn bow(&mut self) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        let bow = BodyPose {
+            shift: [0.0, -BOW_SHIFT, 0.0],
+            pitch: BOW_PITCH,
+            ..BodyPose::NEUTRAL
+        };
+        let res = async {
+            self.body_pose(&stance, &bow).await?;
+            Timer::after_millis(EMOTE_HOLD_MS).await;
+            Ok(())
+        }
+        .await;
+        self.end_at(&stance, res).await
+    }
+
+    /// Lower the body and push it back up, `times` times
+    pub async fn push_ups(&mut self, times: u8) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        let down = BodyPose {
+            shift: [0.0, 0.0, -PUSH_UP_DEPTH],
+            ..BodyPose::NEUTRAL
+        };
+        let res = async {
+            for _ in 0..times {
+                self.body_pose(&stance, &down).await?;
+                self.return_to(&stance).await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.end_at(&stance, res).await
+    }
+
+    /// Sway the body from side to side, `times` times
+    pub async fn shimmy(&mut self, times: u8) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        let res = async {
+            for _ in 0..times {
+                for side in [1.0, -1.0] {
+                    let sway = BodyPose {
+                        shift: [side * SHIMMY_SHIFT, 0.0, 0.0],
+                        roll: side * SHIMMY_ROLL,
+                        ..BodyPose::NEUTRAL
+                    };
+                    self.body_pose(&stance, &sway).await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        self.end_at(&stance, res).await
+    }
+
+    /// Raise a front leg and swing it sideways
+    pub async fn hello(&mut self, leg: Leg) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        let mount = LEG_MOUNTS[leg as usize];
+        let side = if mount.x > 0.0 { 1.0 } else { -1.0 };
+        if let Err(e) = self.body_pose(&stance, &over_others(&stance, leg)?).await {
+            return self.end_at(&stance, Err(e)).await;
+        }
+
+        let speed = self.config.leg_move_speed;
+        let [x_tmp, y_tmp, z_tmp] = self.current_pos[leg];
+        let (bx, by) = mount.to_body(x_tmp, y_tmp);
+        let swung = async {
+            for i in 0..2 * HELLO_WAVES {
+                let swing = if i % 2 == 0 {
+                    HELLO_SWING
+                } else {
+                    -HELLO_SWING
+                };
+                let (x, y) = mount.to_leg(bx + side * swing, by);
+                self.set_site(leg, x, y, HELLO_Z, speed);
+                self.send_cmd().await?;
+            }
+            Ok(())
+        }
+        .await;
+        // the leg comes back down even after a failed swing
+        self.set_site(leg, x_tmp, y_tmp, z_tmp, speed);
+        let lowered = self.send_cmd().await;
+        self.end_at(&stance, swung.and(lowered)).await
+    }
+
+    /// A short sequence of sways, twists and tilts
+    pub async fn dance(&mut self) -> Result<(), MotionError> {
+        let stance = self.current_pos;
+        let res = async {
+            for pose in &DANCE {
+                self.body_pose(&stance, pose).await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.end_at(&stance, res).await
+    }
+
+    /// Move the body to `pose` over the feet of `stance`, all of them on the ground
+    async fn body_pose(&mut self, stance: &Pose, pose: &BodyPose) -> Result<(), MotionError> {
+        let speed = self.config.body_move_speed;
+        for (leg, [x, y, z]) in feet_under(stance, pose).into_iter().enumerate() {
+            self.set_site(leg.into(), x, y, z, speed);
+        }
+        self.send_cmd().await
+    }
+
+    /// End an emote back at `stance`, also after a failed step, then report how it went. A
+    /// faulted engine doesn't move at all, the fault has to be cleared first.
+    async fn end_at(
+        &mut self,
+        stance: &Pose,
+        res: Result<(), MotionError>,
+    ) -> Result<(), MotionError> {
+        let Err(e) = res else {
+            return self.return_to(stance).await;
+        };
+        if self.fault.is_none() {
+            if let Err(back) = self.return_to(stance).await {
+                warn!("[MOTION_TASK] couldn't return to the stance: {back}");
+            }
+        }
+        Err(e)
+    }
+
+    /// Bring every foot back to `stance`, from a body pose
+    async fn return_to(&mut self, stance: &Pose) -> Result<(), MotionError> {
+        let speed = self.config.body_move_speed;
+        for (leg, [x, y, z]) in stance.iter().copied().enumerate() {
+            self.set_site(leg.into(), x, y, z, speed);
+        }
+        self.send_cmd().await
+    }
+}
@@ -0,0 +1,122 @@
+//! Tripod gaits for the hexapod.
+//!
+//! The legs are split in two tripods (front and rear legs of one side with the middle leg of
+//! the other). While one tripod swings, the other one supports the body and pushes it, so the
+//! body is always standing on a triangle.
+//!
+//! Foot targets are computed in the body frame from [`LEG_MOUNTS`] then brought back into
+//! each leg frame, which handles walking and turning the same way.
+use super::{GaitEngine, MotionError};
+use crate::config::*;
+use crate::robot::leg::Leg;
+use micromath::F32Ext;
+
+const TRIPODS: [[Leg; 3]; 2] = [
+    [Leg::FrontLeft, Leg::MiddleRight, Leg::BottomLeft],
+    [Leg::FrontRight, Leg::MiddleLeft, Leg::BottomRight],
+];
+
+/// Neutral position of a foot, in its leg frame
+fn home(leg: Leg) -> (f32, f32) {
+    match leg {
+        Leg::MiddleLeft | Leg::MiddleRight => (X_DEFAULT, Y_START),
+        _ => (X_DEFAULT, Y_START + Y_STEP),
+    }
+}
+
+/// Foot target, in its leg frame, for a body moved by (`dx`, `dy`) and turned by `yaw` from
+/// the neutral stance
+fn foot_target(leg: Leg, dx: f32, dy: f32, yaw: f32) -> (f32, f32) {
+    let mount = LEG_MOUNTS[leg as usize];
+    let (x, y) = home(leg);
+    let (bx, by) = mount.to_body(x, y);
+
+    // The feet move the opposite way of the body
+    let (sin, cos) = (-yaw).sin_cos();
+    let (rx, ry) = (bx * cos - by * sin, bx * sin + by * cos);
+    mount.to_leg(rx - dx, ry - dy)
+}
+
+impl GaitEngine {
+    pub async fn step_forward(&mut self, times: u8) -> Result<(), MotionError> {
+        for _ in 0..times {
+            self.tripod_cycle(0.0, HEX_STRIDE, 0.0).await?;
+        }
+        self.tripod_home().await
+    }
+
+    pub async fn step_backward(&mut self, times: u8) -> Result<(), MotionError> {
+        for _ in 0..times {
+            self.tripod_cycle(0.0, -HEX_STRIDE, 0.0).await?;
+        }
+        self.tripod_home().await
+    }
+
+    pub async fn turn_left(&mut self, times: u8) -> Result<(), MotionError> {
+        for _ in 0..times {
+            self.tripod_cycle(0.0, 0.0, HEX_TURN_ANGLE).await?;
+        }
+        self.tripod_home().await
+    }
+
+    pub async fn turn_right(&mut self, times: u8) -> Result<(), MotionError> {
+        for _ in 0..times {
+            self.tripod_cycle(0.0, 0.0, -HEX_TURN_ANGLE).await?;
+        }
+        self.tripod_home().await
+    }
+
+    /// One step of each tripod: the body travels by (`dx`, `dy`) and turns by `yaw`
+    async fn tripod_cycle(&mut self, dx: f32, dy: f32, yaw: f32) -> Result<(), MotionError> {
+        let leg_speed = self.config.leg_move_speed;
+        let body_speed = self.config.body_move_speed;
+
+        for (i, swing) in TRIPODS.iter().enumerate() {
+            let stance = TRIPODS[1 - i];
+
+            for &leg in swing {
+                self.set_site(leg, KEEP, KEEP, Z_UP, leg_speed);
+            }
+            self.send_cmd().await?;
+
+            // Swing legs reach forward while the stance legs push the body
+            for &leg in swing {
+                let (x, y) = foot_target(leg, -dx / 2.0, -dy / 2.0, -yaw / 2.0);
+                self.set_site(leg, x, y, Z_UP, body_speed);
+            }
+            for leg in stance {
+                let (x, y) = foot_target(leg, dx / 2.0, dy / 2.0, yaw / 2.0);
+                self.set_site(leg, x, y, Z_DEFAULT, body_speed);
+            }
+            self.send_cmd().await?;
+
+            for &leg in swing {
+                self.set_site(leg, KEEP, KEEP, Z_DEFAULT, leg_speed);
+            }
+            self.send_cmd().await?;
+        }
+        Ok(())
+    }
+
+    /// Bring every foot back to the neutral stance, one tripod at a time
+    async fn tripod_home(&mut self) -> Result<(), MotionError> {
+        let leg_speed = self.config.leg_move_speed;
+
+        for tripod in TRIPODS {
+            for leg in tripod {
+                self.set_site(leg, KEEP, KEEP, Z_UP, leg_speed);
+            }
+            self.send_cmd().await?;
+            for leg in tripod {
+                let (x, y) = home(leg);
+                self.set_site(leg, x, y, Z_UP, leg_speed);
+            }
+            self.send_cmd().await?;
+            for leg in tripod {
+                self.set_site(leg, KEEP, KEEP, Z_DEFAULT, leg_speed);
+            }
+            self.send_cmd().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! Joint limits and reachable workspace of the legs.
+//!
+//! A foot target is reachable when:
+//! - it stays [`FOOT_MIN_X`] away from the coxa along the leg x axis, out of the body,
+//! - its distance to the femur joint is between the folded and the stretched leg, with
+//!   [`REACH_MARGIN`] left at both ends,
+//! - every servo of the leg stays within the `min` and `max` angles of the robot description.
+//!
+//! The servo limits depend on how each servo is mounted, so the workspace differs from one leg
+//! to the other. The gait engine rejects a movement with an unreachable target, the servo task
+//! clamps the servo angles as a second line of defence.
+use crate::config::*;
+use crate::kinematics::conversion::{cartesian_to_polar, polar_to_servo};
+use crate::robot::{joint::Joint, leg::Leg};
+use core::fmt::Display;
+use micromath::F32Ext;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LimitError {
+    /// The foot would come closer to the body than [`FOOT_MIN_X`]
+    TooClose(Leg),
+    /// The leg is too short, or can't fold enough, to reach the foot
+    OutOfReach(Leg),
+    /// The servo of a joint would go past its limits
+    Joint(Leg, Joint),
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimitError::TooClose(leg) => write!(f, "{leg} foot too close to the body"),
+            LimitError::OutOfReach(leg) => write!(f, "{leg} foot out of reach"),
+            LimitError::Joint(leg, joint) => write!(f, "{leg} {joint} past its limits"),
+        }
+    }
+}
+
+/// Check that `leg` can put its foot at `foot`, in its leg frame
+pub fn check(leg: Leg, foot: [f32; 3]) -> Result<(), LimitError> {
+    let [x, y, z] = foot;
+    if x < FOOT_MIN_X {
+        return Err(LimitError::TooClose(leg));
+    }
+
+    let v = (x.powi(2) + y.powi(2)).sqrt() - LENGTH_C;
+    let d = (v.powi(2) + z.powi(2)).sqrt();
+    let reach = (LENGTH_A - LENGTH_B).abs() + REACH_MARGIN..=LENGTH_A + LENGTH_B - REACH_MARGIN;
+    if !reach.contains(&d) {
+        return Err(LimitError::OutOfReach(leg));
+    }
+
+    let (alpha, beta, gamma) = cartesian_to_polar(x, y, z);
+    let angles = polar_to_servo(leg, alpha, beta, gamma);
+    for (joint, (servo, angle)) in SERVO_MAP[leg as usize].iter().zip(angles).enumerate() {
+        if !servo.allows(angle) {
+            return Err(LimitError::Joint(leg, joint.into()));
+        }
+    }
+    Ok(())
+}
@@ -6,7 +6,11 @@
 //!
 //! - [`conversion`] handles forward/inverse kinematics and servo pulse mapping.
 //! - [`gait_engine`] implements the state machine for coordinated leg movement.
+//! - [`stability`] checks the body is held by the feet on the ground.
+//! - [`limits`] keeps the feet within reach and the servos within their stops.
 //!
 //! Used by the motion task to plan and execute robot movement.
 pub mod conversion;
 pub mod gait_engine;
+pub mod limits;
+pub mod stability;
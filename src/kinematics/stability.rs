@@ -0,0 +1,137 @@
+//! Static stability of the body over its feet.
+//!
+//! The robot stands still without tipping while its centre of mass, taken at the centre of the
+//! body, stays inside the support polygon: the convex hull of the feet on the ground, seen from
+//! above. The stability margin is the distance from the centre of the body to the closest edge
+//! of that polygon, negative when outside.
+//!
+//! Points are in the body frame (x to the right, y forward, mm). The gait engine uses this
+//! module to move the body over the feet left on the ground before a leg lifts, until the
+//! margin reaches [`STABILITY_MARGIN`](crate::config::STABILITY_MARGIN).
+use crate::config::{LEG_MOUNTS, LIFT_THRESHOLD};
+use crate::robot::leg::{Pose, LEG_COUNT};
+use heapless::Vec;
+use micromath::F32Ext;
+
+/// A position seen from above, in the body frame
+pub type Point = (f32, f32);
+
+/// Vertices of a support polygon, counterclockwise
+pub type Polygon = Vec<Point, LEG_COUNT>;
+
+/// Bisection steps of [`balance_shift`], well under a tenth of mm for the shifts at hand
+const SHIFT_STEPS: usize = 12;
+
+/// Legs whose foot stays on the ground during the movement from `current` to `expected`,
+/// `None` if no foot leaves the ground.
+///
+/// The ground is at the lowest foot, the feet within [`LIFT_THRESHOLD`] of it are planted. A
+/// planted foot rising by more than [`LIFT_THRESHOLD`] is lifting.
+pub fn planted_during(current: &Pose, expected: &Pose) -> Option<[bool; LEG_COUNT]> {
+    let ground = current
+        .iter()
+        .map(|foot| foot[2])
+        .fold(f32::INFINITY, f32::min);
+    let mut planted = [false; LEG_COUNT];
+    let mut lifting = false;
+    for (leg, (from, to)) in current.iter().zip(expected).enumerate() {
+        let on_ground = from[2] - ground < LIFT_THRESHOLD;
+        let lifts = on_ground && to[2] - from[2] > LIFT_THRESHOLD;
+        planted[leg] = on_ground && !lifts;
+        lifting |= lifts;
+    }
+    lifting.then_some(planted)
+}
+
+/// Convex hull of the `planted` feet of `pose`
+pub fn support_polygon(pose: &Pose, planted: &[bool; LEG_COUNT]) -> Polygon {
+    let mut points: Polygon = pose
+        .iter()
+        .zip(&LEG_MOUNTS)
+        .zip(planted)
+        .filter(|(_, planted)| **planted)
+        .map(|((foot, mount), _)| mount.to_body(foot[0], foot[1]))
+        .collect();
+    points.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal));
+
+    // Andrew's monotone chain, lower then upper hull
+    let mut hull: Vec<Point, { 2 * LEG_COUNT }> = Vec::new();
+    for &point in &points {
+        while hull.len() >= 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.0 {
+            hull.pop();
+        }
+        let _ = hull.push(point);
+    }
+    let lower = hull.len() + 1;
+    for &point in points.iter().rev().skip(1) {
+        while hull.len() >= lower && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.0
+        {
+            hull.pop();
+        }
+        let _ = hull.push(point);
+    }
+    // the upper hull ends on the first point
+    hull.pop();
+    hull.into_iter().collect()
+}
+
+/// Distance from `point` to the closest edge of `polygon`, negative outside. `None` if the
+/// polygon has no area, which can't hold the body.
+pub fn margin(polygon: &Polygon, point: Point) -> Option<f32> {
+    if polygon.len() < 3 {
+        return None;
+    }
+    let edges = polygon.iter().zip(polygon.iter().cycle().skip(1));
+    edges
+        .map(|(&a, &b)| {
+            let length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+            cross(a, b, point) / length
+        })
+        .reduce(f32::min)
+}
+
+/// Smallest move of the body toward the centre of `polygon` that brings its margin to
+/// `min_margin`, `None` if the body is stable enough where it is or can't be held at all.
+///
+/// When the polygon is too small for `min_margin`, the body goes to its centre, the best it
+/// can do.
+pub fn balance_shift(polygon: &Polygon, min_margin: f32) -> Option<Point> {
+    if margin(polygon, (0.0, 0.0))? >= min_margin {
+        return None;
+    }
+    let count = polygon.len() as f32;
+    let centre = polygon.iter().fold((0.0, 0.0), |sum, p| {
+        (sum.0 + p.0 / count, sum.1 + p.1 / count)
+    });
+    if margin(polygon, centre)? < min_margin {
+        return Some(centre);
+    }
+
+    // The margin is concave along the way, so it stays above `min_margin` once it got there
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..SHIFT_STEPS {
+        let t = (low + high) / 2.0;
+        match margin(polygon, (centre.0 * t, centre.1 * t)) {
+            Some(m) if m >= min_margin => high = t,
+            _ => low = t,
+        }
+    }
+    Some((centre.0 * high, centre.1 * high))
+}
+
+/// Feet of `pose` in their leg frames once the body moved by `shift` over them
+pub fn shift_body(pose: &Pose, shift: Point) -> Pose {
+    let mut feet = *pose;
+    for (foot, mount) in feet.iter_mut().zip(&LEG_MOUNTS) {
+        let (bx, by) = mount.to_body(foot[0], foot[1]);
+        let (x, y) = mount.to_leg(bx - shift.0, by - shift.1);
+        foot[0] = x;
+        foot[1] = y;
+    }
+    feet
+}
+
+/// Positive when `o`, `a`, `b` turn counterclockwise
+fn cross(o: Point, a: Point, b: Point) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
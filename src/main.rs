@@ -14,27 +14,43 @@
 
 extern crate alloc;
 
+pub mod auth;
+pub mod board;
 pub mod config;
+pub mod hex;
 pub mod kinematics;
+pub mod ota;
 pub mod robot;
+pub mod settings;
 pub mod tasks;
 
-use crate::config::{SERVOCMD_CHANNEL_SIZE, TCPCMD_CHANNEL_SIZE};
-use crate::robot::commands::{ServoCommand, TcpCommand};
+use crate::board::{i2c_config, i2c_pins};
+use crate::config::{AUXCMD_CHANNEL_SIZE, SERVOCMD_CHANNEL_SIZE, TCPCMD_CHANNEL_SIZE};
+use crate::robot::commands::{AuxCommand, ServoCommand, TcpCommand};
+use crate::tasks::ble_task::{ble_task, BleController};
 use crate::tasks::gait_task::gait_task;
-use crate::tasks::net_task::{configurate_and_start_wifi, net_task, runner_task};
-use crate::tasks::servo_task::servo_task;
+use crate::tasks::http_task::http_task;
+use crate::tasks::mdns_task::mdns_task;
+use crate::tasks::mqtt_task::mqtt_task;
+use crate::tasks::net_task::{net_task, runner_task};
+use crate::tasks::ota_task::ota_task;
+use crate::tasks::servo_task::{servo_task, I2cBus, ServoDrivers};
+use crate::tasks::udp_task::udp_task;
+use crate::tasks::wifi_task::wifi_task;
 
 use core::future::pending;
+
 use embassy_executor::Spawner;
 use embassy_net::{Config as NetConfig, StackResources};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
-use esp_hal::i2c::master::{Config, I2c};
+use esp_hal::i2c::master::I2c;
+use esp_hal::rtc_cntl::Rtc;
 use esp_hal::timer::timg::TimerGroup;
-use pwm_pca9685::Pca9685;
+use esp_wifi::ble::controller::BleConnector;
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
@@ -42,6 +58,8 @@ static TCP_CMD_CHANNEL: Channel<CriticalSectionRawMutex, TcpCommand, TCPCMD_CHAN
     Channel::new();
 static SERVO_CMD_CHANNEL: Channel<CriticalSectionRawMutex, ServoCommand, SERVOCMD_CHANNEL_SIZE> =
     Channel::new();
+static AUX_CMD_CHANNEL: Channel<CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE> =
+    Channel::new();
 
 macro_rules! mk_static {
     ($t:ty, $val:expr) => {{
@@ -71,36 +89,83 @@ async fn main(spawner: Spawner) {
         .expect("Failed to initialize WIFI/BLE controller");
     let wifi_init = mk_static!(esp_wifi::EspWifiController, wifi_init);
 
-    let (mut wifi_controller, interfaces) = esp_wifi::wifi::new(wifi_init, p.WIFI).unwrap();
-    configurate_and_start_wifi(&mut wifi_controller).await;
+    // Bluetooth shares the radio with the Wi-Fi
+    let ble_controller = BleController::new(BleConnector::new(wifi_init, p.BT));
+
+    // joined by the wifi task with the credentials provisioned over BLE
+    let (wifi_controller, interfaces) = esp_wifi::wifi::new(wifi_init, p.WIFI).unwrap();
 
     //Get the embassy net stack up and working.
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    auth::init(rng);
     let config = NetConfig::dhcpv4(Default::default());
     let device = interfaces.sta;
     let (stack, runner) = embassy_net::new(
         device,
         config,
-        mk_static!(StackResources<3>, StackResources::new()),
+        mk_static!(StackResources<10>, StackResources::new()), // dhcp, dns, tcp, 3 http servers, udp, mdns, mqtt, ota
         seed,
     );
 
-    // I2c
-    let i2c_dev = I2c::new(p.I2C0, Config::default())
+    // I2c, pins and bus speed depend on the board revision
+    let (sda, scl) = i2c_pins!(p);
+    let i2c_dev = I2c::new(p.I2C0, i2c_config())
         .unwrap()
-        .with_sda(p.GPIO21)
-        .with_scl(p.GPIO22)
+        .with_sda(sda)
+        .with_scl(scl)
         .into_async();
 
-    //Pca9685
-    let pwm = Pca9685::new(i2c_dev, pwm_pca9685::Address::from(0x7f)).unwrap();
+    //Pca9685s sharing the bus, they are configured by the servo task
+    let i2c_bus = mk_static!(I2cBus, Mutex::new(i2c_dev));
+    let drivers = ServoDrivers::new(i2c_bus);
 
+    spawner
+        .spawn(wifi_task(wifi_controller))
+        .expect("Fail spawning wifi task");
     spawner
         .spawn(runner_task(runner))
         .expect("Fail spawning runner task");
     spawner
-        .spawn(net_task(stack, TCP_CMD_CHANNEL.sender()))
+        .spawn(net_task(
+            stack,
+            TCP_CMD_CHANNEL.sender(),
+            AUX_CMD_CHANNEL.sender(),
+        ))
         .expect("Fail spawning net task");
+    // the web panel polls the state while sending commands, a WebSocket holds a server
+    for _ in 0..3 {
+        spawner
+            .spawn(http_task(
+                stack,
+                TCP_CMD_CHANNEL.sender(),
+                AUX_CMD_CHANNEL.sender(),
+            ))
+            .expect("Fail spawning http task");
+    }
+    spawner
+        .spawn(ble_task(
+            ble_controller,
+            TCP_CMD_CHANNEL.sender(),
+            AUX_CMD_CHANNEL.sender(),
+        ))
+        .expect("Fail spawning ble task");
+    spawner
+        .spawn(mdns_task(stack))
+        .expect("Fail spawning mdns task");
+    spawner
+        .spawn(mqtt_task(
+            stack,
+            TCP_CMD_CHANNEL.sender(),
+            AUX_CMD_CHANNEL.sender(),
+        ))
+        .expect("Fail spawning mqtt task");
+    // the RTC watchdog guards the trial of an update
+    spawner
+        .spawn(ota_task(stack, Rtc::new(p.LPWR)))
+        .expect("Fail spawning ota task");
+    spawner
+        .spawn(udp_task(stack, TCP_CMD_CHANNEL.sender()))
+        .expect("Fail spawning udp task");
     spawner
         .spawn(gait_task(
             TCP_CMD_CHANNEL.receiver(),
@@ -109,7 +174,11 @@ async fn main(spawner: Spawner) {
         .expect("Fail spawning the motion task"); // listen for commands forwarded from the tcp
                                                   // server
     spawner
-        .spawn(servo_task(pwm, SERVO_CMD_CHANNEL.receiver()))
+        .spawn(servo_task(
+            drivers,
+            SERVO_CMD_CHANNEL.receiver(),
+            AUX_CMD_CHANNEL.receiver(),
+        ))
         .expect("Fail spawning servo task");
 
     loop {
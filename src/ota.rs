@@ -0,0 +1,381 @@
+//! Over-the-air firmware updates: app slots, image writing and boot selection.
+//!
+//! The firmware runs from one of the two app partitions `ota_0` and `ota_1` (see
+//! `partitions.csv`) and an update is written to the other one. The bootloader starts the
+//! partition of the `otadata` entry with the highest sequence number, `ota_0` when `otadata` is
+//! erased as after flashing over USB.
+//!
+//! An image is only booted once its SHA-256 checksum and its Ed25519 signature, by the key
+//! given at build time in `OTA_PUBLIC_KEY`, are verified. It then boots on trial: its entry is
+//! marked pending until [`confirm`], otherwise [`roll_back`] brings back the previous firmware.
+//! A trial still pending at boot means the new firmware crashed or hung, it is rolled back.
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use ed25519_compact::{PublicKey, Signature, VerifyingState};
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::ReadStorage;
+use esp_storage::FlashStorage;
+use log::info;
+use sha2::{Digest, Sha256};
+
+include!(concat!(env!("OUT_DIR"), "/ota_key.rs"));
+
+const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const PARTITION_TABLE_SIZE: usize = 0xc00;
+const PARTITION_ENTRY_SIZE: usize = 32;
+const PARTITION_MAGIC: [u8; 2] = [0xaa, 0x50];
+const TYPE_APP: u8 = 0x00;
+const TYPE_DATA: u8 = 0x01;
+const SUBTYPE_OTADATA: u8 = 0x00;
+const SUBTYPE_OTA_0: u8 = 0x10;
+const SUBTYPE_OTA_1: u8 = 0x11;
+
+const SECTOR_SIZE: usize = 4096;
+/// Flash writes are made of whole words
+const WORD_SIZE: usize = 4;
+/// First byte of an ESP32 app image
+const IMAGE_MAGIC: u8 = 0xe9;
+
+/// `esp_ota_select_entry_t`: sequence, label, state and checksum of the sequence
+const ENTRY_SIZE: usize = 32;
+const STATE_NEW: u32 = 0;
+const STATE_PENDING_VERIFY: u32 = 1;
+const STATE_VALID: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaError {
+    /// The partition table has no `otadata`, `ota_0` and `ota_1` partitions
+    Unsupported,
+    /// The firmware was built without `OTA_PUBLIC_KEY`
+    Disabled,
+    Flash,
+    TooLarge,
+    /// Not an app image, or not as long as announced
+    InvalidImage,
+    Checksum,
+    Signature,
+}
+
+impl Display for OtaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OtaError::Unsupported => f.write_str("no OTA partitions"),
+            OtaError::Disabled => f.write_str("OTA disabled, no public key"),
+            OtaError::Flash => f.write_str("flash access failed"),
+            OtaError::TooLarge => f.write_str("image larger than the partition"),
+            OtaError::InvalidImage => f.write_str("invalid image"),
+            OtaError::Checksum => f.write_str("checksum mismatch"),
+            OtaError::Signature => f.write_str("invalid signature"),
+        }
+    }
+}
+
+/// What the running firmware must do at boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCheck {
+    /// Flashed over USB or already confirmed
+    Confirmed,
+    /// First boot of an update, to confirm
+    Trial,
+    /// The update crashed or hung during its trial
+    FailedTrial,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Partition {
+    offset: u32,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SelectEntry {
+    seq: u32,
+    state: u32,
+}
+
+impl SelectEntry {
+    fn decode(data: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let seq = word(0);
+        (seq != u32::MAX && word(28) == entry_crc(seq)).then_some(Self {
+            seq,
+            state: word(24),
+        })
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut data = [0xff; ENTRY_SIZE];
+        data[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        data[24..28].copy_from_slice(&self.state.to_le_bytes());
+        data[28..32].copy_from_slice(&entry_crc(self.seq).to_le_bytes());
+        data
+    }
+
+    /// App slot booted by the entry, the sequence counts from 1 for `ota_0`
+    fn slot(&self) -> usize {
+        (self.seq.wrapping_sub(1) % 2) as usize
+    }
+}
+
+/// CRC-32 of the sequence number, as computed by the bootloader
+fn entry_crc(seq: u32) -> u32 {
+    let mut crc = 0u32;
+    for byte in seq.to_le_bytes() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The OTA partitions
+struct Layout {
+    otadata: Partition,
+    apps: [Partition; 2],
+}
+
+impl Layout {
+    fn read(flash: &mut FlashStorage) -> Result<Self, OtaError> {
+        let mut table = vec![0u8; PARTITION_TABLE_SIZE];
+        flash
+            .read(PARTITION_TABLE_OFFSET, &mut table)
+            .map_err(|_| OtaError::Flash)?;
+
+        let (mut otadata, mut ota_0, mut ota_1) = (None, None, None);
+        for entry in table.chunks_exact(PARTITION_ENTRY_SIZE) {
+            if entry[..2] != PARTITION_MAGIC {
+                break;
+            }
+            let word =
+                |i: usize| u32::from_le_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+            let partition = Partition {
+                offset: word(4),
+                size: word(8),
+            };
+            match (entry[2], entry[3]) {
+                (TYPE_DATA, SUBTYPE_OTADATA) => otadata = Some(partition),
+                (TYPE_APP, SUBTYPE_OTA_0) => ota_0 = Some(partition),
+                (TYPE_APP, SUBTYPE_OTA_1) => ota_1 = Some(partition),
+                _ => {}
+            }
+        }
+        match (otadata, ota_0, ota_1) {
+            (Some(otadata), Some(ota_0), Some(ota_1)) => Ok(Self {
+                otadata,
+                apps: [ota_0, ota_1],
+            }),
+            _ => Err(OtaError::Unsupported),
+        }
+    }
+
+    /// The entry followed by the bootloader and the `otadata` sector holding it
+    fn current(&self, flash: &mut FlashStorage) -> Result<Option<(usize, SelectEntry)>, OtaError> {
+        let mut current: Option<(usize, SelectEntry)> = None;
+        for sector in 0..2 {
+            let mut data = [0u8; ENTRY_SIZE];
+            flash
+                .read(self.sector_offset(sector), &mut data)
+                .map_err(|_| OtaError::Flash)?;
+            if let Some(entry) = SelectEntry::decode(&data) {
+                if current.is_none_or(|(_, best)| entry.seq > best.seq) {
+                    current = Some((sector, entry));
+                }
+            }
+        }
+        Ok(current)
+    }
+
+    fn write_entry(
+        &self,
+        flash: &mut FlashStorage,
+        sector: usize,
+        entry: SelectEntry,
+    ) -> Result<(), OtaError> {
+        let offset = self.sector_offset(sector);
+        flash
+            .erase(offset, offset + SECTOR_SIZE as u32)
+            .and_then(|()| flash.write(offset, &entry.encode()))
+            .map_err(|_| OtaError::Flash)
+    }
+
+    fn sector_offset(&self, sector: usize) -> u32 {
+        self.otadata.offset + (sector * SECTOR_SIZE) as u32
+    }
+}
+
+/// Find out whether the running firmware is on trial, starting the trial of a new image
+pub fn check_boot() -> Result<BootCheck, OtaError> {
+    let mut flash = FlashStorage::new();
+    let layout = Layout::read(&mut flash)?;
+    let Some((sector, entry)) = layout.current(&mut flash)? else {
+        return Ok(BootCheck::Confirmed);
+    };
+    match entry.state {
+        STATE_NEW => {
+            let pending = SelectEntry {
+                state: STATE_PENDING_VERIFY,
+                ..entry
+            };
+            layout.write_entry(&mut flash, sector, pending)?;
+            Ok(BootCheck::Trial)
+        }
+        STATE_PENDING_VERIFY => Ok(BootCheck::FailedTrial),
+        _ => Ok(BootCheck::Confirmed),
+    }
+}
+
+/// Keep the running firmware
+pub fn confirm() -> Result<(), OtaError> {
+    let mut flash = FlashStorage::new();
+    let layout = Layout::read(&mut flash)?;
+    if let Some((sector, entry)) = layout.current(&mut flash)? {
+        let valid = SelectEntry {
+            state: STATE_VALID,
+            ..entry
+        };
+        layout.write_entry(&mut flash, sector, valid)?;
+    }
+    Ok(())
+}
+
+/// Boot the previous firmware next, the caller resets the chip
+pub fn roll_back() -> Result<(), OtaError> {
+    let mut flash = FlashStorage::new();
+    let layout = Layout::read(&mut flash)?;
+    if let Some((sector, entry)) = layout.current(&mut flash)? {
+        // the next sequence number points to the other slot
+        let previous = SelectEntry {
+            seq: entry.seq + 1,
+            state: STATE_VALID,
+        };
+        layout.write_entry(&mut flash, 1 - sector, previous)?;
+    }
+    Ok(())
+}
+
+/// An image being written to the inactive slot
+pub struct Update {
+    flash: FlashStorage,
+    layout: Layout,
+    current: Option<(usize, SelectEntry)>,
+    slot: usize,
+    size: u32,
+    received: u32,
+    /// Bytes of the sector being received, erased and written once complete
+    sector: Vec<u8>,
+    flushed: u32,
+    checksum: Sha256,
+    signature: VerifyingState,
+}
+
+impl Update {
+    /// Prepare the inactive slot for an image of `size` bytes signed with `signature`
+    pub fn begin(size: u32, signature: &[u8; 64]) -> Result<Self, OtaError> {
+        let key = OTA_PUBLIC_KEY.ok_or(OtaError::Disabled)?;
+        let signature = PublicKey::new(key)
+            .verify_incremental(&Signature::new(*signature))
+            .map_err(|_| OtaError::Signature)?;
+
+        let mut flash = FlashStorage::new();
+        let layout = Layout::read(&mut flash)?;
+        let current = layout.current(&mut flash)?;
+        let running = current.map_or(0, |(_, entry)| entry.slot());
+        let slot = 1 - running;
+        let partition = layout.apps[slot];
+        if size > partition.size {
+            return Err(OtaError::TooLarge);
+        }
+        info!(
+            "[OTA] writing {} bytes to ota_{} at {:#x}",
+            size, slot, partition.offset
+        );
+
+        Ok(Self {
+            flash,
+            layout,
+            current,
+            slot,
+            size,
+            received: 0,
+            sector: Vec::with_capacity(SECTOR_SIZE),
+            flushed: 0,
+            checksum: Sha256::new(),
+            signature,
+        })
+    }
+
+    /// Bytes still expected
+    pub fn remaining(&self) -> u32 {
+        self.size - self.received
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), OtaError> {
+        if self.received == 0 && data.first().is_some_and(|byte| *byte != IMAGE_MAGIC) {
+            return Err(OtaError::InvalidImage);
+        }
+        if data.len() > self.remaining() as usize {
+            return Err(OtaError::TooLarge);
+        }
+        self.received += data.len() as u32;
+        self.checksum.update(data);
+        self.signature.absorb(data);
+
+        while !data.is_empty() {
+            let n = (SECTOR_SIZE - self.sector.len()).min(data.len());
+            self.sector.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.sector.len() == SECTOR_SIZE {
+                self.flush_sector()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_sector(&mut self) -> Result<(), OtaError> {
+        let offset = self.layout.apps[self.slot].offset + self.flushed;
+        let padded = self.sector.len().next_multiple_of(WORD_SIZE);
+        self.sector.resize(padded, 0xff);
+        self.flash
+            .erase(offset, offset + SECTOR_SIZE as u32)
+            .and_then(|()| self.flash.write(offset, &self.sector))
+            .map_err(|_| OtaError::Flash)?;
+        self.flushed += SECTOR_SIZE as u32;
+        self.sector.clear();
+        Ok(())
+    }
+
+    /// Verify the image against its SHA-256 `checksum` and its signature, then boot it next on
+    /// trial
+    pub fn finish(mut self, checksum: &[u8; 32]) -> Result<(), OtaError> {
+        if self.remaining() > 0 {
+            return Err(OtaError::InvalidImage);
+        }
+        if !self.sector.is_empty() {
+            self.flush_sector()?;
+        }
+        if self.checksum.finalize().as_slice() != checksum {
+            return Err(OtaError::Checksum);
+        }
+        self.signature.verify().map_err(|_| OtaError::Signature)?;
+
+        let (seq, sector) = match self.current {
+            Some((sector, entry)) => (entry.seq + 1, 1 - sector),
+            None => (self.slot as u32 + 1, 0),
+        };
+        let entry = SelectEntry {
+            seq,
+            state: STATE_NEW,
+        };
+        self.layout.write_entry(&mut self.flash, sector, entry)?;
+        info!("[OTA] ota_{} boots next", self.slot);
+        Ok(())
+    }
+}
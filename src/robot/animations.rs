@@ -0,0 +1,252 @@
+//! Keyframe animations stored on the robot.
+//!
+//! An animation is a list of poses of the feet, the keyframes, each with the time taken to
+//! reach it from the previous one. It is recorded by moving the feet with any motion command,
+//! `foot` usually, and capturing the pose reached:
+//!
+//! `anim record nod; stand; anim key 500; foot fl 60 80 50; anim key 300; anim save`
+//!
+//! The capture and the save go through the command queue of the gait task, so they happen
+//! once the motions sent before them are done. When played, every foot moves in a straight
+//! line to each keyframe in turn, all of them arriving after its duration. [`STOP_MACRO`]
+//! stops an animation on its way to a keyframe, the robot then stands where it is.
+//!
+//! Animations live in the sectors of the `nvs` partition following the settings,
+//! postcard-encoded one after the other with the coordinates in tenths of mm. An animation
+//! never spans two sectors, the next one starts once a sector can't hold it.
+//!
+//! [`STOP_MACRO`]: crate::robot::macros::STOP_MACRO
+extern crate alloc;
+
+use crate::config::{ANIMATION_COUNT, ANIMATION_NAME_SIZE, KEYFRAME_COUNT};
+use crate::robot::leg::{Pose, LEG_COUNT};
+use crate::robot::macros::valid_name;
+use crate::settings::{self, SettingsError, RECORDS_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::Display;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::mutex::Mutex;
+use heapless::String;
+use log::warn;
+use micromath::F32Ext;
+use serde::{Deserialize, Serialize};
+
+pub type AnimationName = String<ANIMATION_NAME_SIZE>;
+
+/// Sectors of the `nvs` partition after the settings
+const ANIMATION_SECTORS: [u32; 3] = [0xA000, 0xB000, 0xC000];
+const MAGIC: [u8; 4] = *b"ANIM";
+/// Longest encoding of an animation: a varint takes up to 3 bytes for 16 bits, 1 for a length
+const ANIMATION_MAX_SIZE: usize =
+    1 + ANIMATION_NAME_SIZE + 1 + KEYFRAME_COUNT * (LEG_COUNT * 3 + 1) * 3;
+
+const _: () = assert!(
+    ANIMATION_SECTORS.len() * (RECORDS_SIZE / ANIMATION_MAX_SIZE) >= ANIMATION_COUNT,
+    "ANIMATION_COUNT animations of KEYFRAME_COUNT keyframes don't fit in flash"
+);
+
+/// Serializes the updates, each one reading then writing the whole sector
+static ANIMATIONS_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+/// The animation being recorded, if any
+static RECORDING: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Animation>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AnimationError {
+    /// The name isn't made of letters, digits, `-` and `_`
+    InvalidName,
+    Unknown,
+    /// No animation is being recorded
+    NotRecording,
+    /// No keyframe was recorded
+    Empty,
+    /// [`ANIMATION_COUNT`] animations are stored already
+    Full,
+    /// [`KEYFRAME_COUNT`] keyframes are recorded already
+    TooManyKeyframes,
+    Settings(SettingsError),
+}
+
+impl Display for AnimationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AnimationError::InvalidName => f.write_str("invalid animation name"),
+            AnimationError::Unknown => f.write_str("unknown animation"),
+            AnimationError::NotRecording => f.write_str("no animation being recorded"),
+            AnimationError::Empty => f.write_str("no keyframe recorded"),
+            AnimationError::Full => {
+                write!(f, "no room for more than {ANIMATION_COUNT} animations")
+            }
+            AnimationError::TooManyKeyframes => {
+                write!(f, "no room for more than {KEYFRAME_COUNT} keyframes")
+            }
+            AnimationError::Settings(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A pose of the feet and the time to reach it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Tenths of mm, in the leg frames
+    feet: [[i16; 3]; LEG_COUNT],
+    pub duration_ms: u16,
+}
+
+impl Keyframe {
+    pub fn new(pose: &Pose, duration_ms: u16) -> Self {
+        let mut feet = [[0; 3]; LEG_COUNT];
+        for (foot, position) in feet.iter_mut().zip(pose) {
+            for (coordinate, value) in foot.iter_mut().zip(position) {
+                *coordinate = (value * 10.0).round() as i16;
+            }
+        }
+        Self { feet, duration_ms }
+    }
+
+    pub fn pose(&self) -> Pose {
+        let mut pose = [[0.0; 3]; LEG_COUNT];
+        for (position, foot) in pose.iter_mut().zip(&self.feet) {
+            for (value, coordinate) in position.iter_mut().zip(foot) {
+                *value = *coordinate as f32 / 10.0;
+            }
+        }
+        pose
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Animation {
+    pub name: AnimationName,
+    pub keyframes: heapless::Vec<Keyframe, KEYFRAME_COUNT>,
+}
+
+/// Start recording an animation, dropping the one being recorded
+pub fn record(name: &str) -> Result<(), AnimationError> {
+    if !valid_name(name) {
+        return Err(AnimationError::InvalidName);
+    }
+    let name = AnimationName::try_from(name).map_err(|_| AnimationError::InvalidName)?;
+    let stored = load();
+    if stored.len() >= ANIMATION_COUNT && !stored.iter().any(|a| a.name == name) {
+        return Err(AnimationError::Full);
+    }
+    let animation = Animation {
+        name,
+        keyframes: heapless::Vec::new(),
+    };
+    RECORDING.lock(|recording| recording.replace(Some(animation)));
+    Ok(())
+}
+
+pub fn recording() -> bool {
+    RECORDING.lock(|recording| recording.borrow().is_some())
+}
+
+/// Append `pose` to the animation being recorded
+pub fn add_keyframe(pose: &Pose, duration_ms: u16) -> Result<(), AnimationError> {
+    RECORDING.lock(|recording| {
+        let mut recording = recording.borrow_mut();
+        let animation = recording.as_mut().ok_or(AnimationError::NotRecording)?;
+        animation
+            .keyframes
+            .push(Keyframe::new(pose, duration_ms))
+            .map_err(|_| AnimationError::TooManyKeyframes)
+    })
+}
+
+/// Store the animation being recorded, replacing the one of the same name
+pub async fn save() -> Result<AnimationName, AnimationError> {
+    let animation = RECORDING
+        .lock(|recording| recording.borrow().clone())
+        .ok_or(AnimationError::NotRecording)?;
+    if animation.keyframes.is_empty() {
+        return Err(AnimationError::Empty);
+    }
+
+    let _lock = ANIMATIONS_LOCK.lock().await;
+    let mut stored = load();
+    stored.retain(|a| a.name != animation.name);
+    if stored.len() >= ANIMATION_COUNT {
+        return Err(AnimationError::Full);
+    }
+    let name = animation.name.clone();
+    stored.push(animation);
+    store(&stored).map_err(AnimationError::Settings)?;
+    // kept on failure for another try, unless a new recording started meanwhile
+    RECORDING.lock(|recording| recording.borrow_mut().take_if(|a| a.name == name));
+    Ok(name)
+}
+
+pub async fn delete(name: &str) -> Result<(), AnimationError> {
+    let _lock = ANIMATIONS_LOCK.lock().await;
+    let mut stored = load();
+    let count = stored.len();
+    stored.retain(|a| a.name != name);
+    if stored.len() == count {
+        return Err(AnimationError::Unknown);
+    }
+    store(&stored).map_err(AnimationError::Settings)
+}
+
+/// Names of the stored animations
+pub fn list() -> Vec<AnimationName> {
+    load().into_iter().map(|a| a.name).collect()
+}
+
+pub fn find(name: &str) -> Result<Animation, AnimationError> {
+    load()
+        .into_iter()
+        .find(|a| a.name == name)
+        .ok_or(AnimationError::Unknown)
+}
+
+/// Animations stored in flash, none if the sectors don't hold any
+fn load() -> Vec<Animation> {
+    let mut animations = Vec::new();
+    for offset in ANIMATION_SECTORS {
+        let Some(records) = settings::read_sector(offset, MAGIC) else {
+            continue;
+        };
+        let mut rest = records.as_slice();
+        while !rest.is_empty() {
+            match postcard::take_from_bytes::<Animation>(rest) {
+                Ok((animation, tail)) => {
+                    animations.push(animation);
+                    rest = tail;
+                }
+                Err(e) => {
+                    warn!("Invalid animation stored, ignoring the rest: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+    animations
+}
+
+/// Fill the sectors in turn, only writing the ones that changed
+fn store(animations: &[Animation]) -> Result<(), SettingsError> {
+    let mut sectors = vec![Vec::new(); ANIMATION_SECTORS.len()];
+    let mut sector = 0;
+    let mut buffer = vec![0u8; ANIMATION_MAX_SIZE];
+    for animation in animations {
+        let encoded =
+            postcard::to_slice(animation, &mut buffer).map_err(|_| SettingsError::TooLarge)?;
+        if sectors[sector].len() + encoded.len() > RECORDS_SIZE {
+            sector += 1;
+        }
+        sectors
+            .get_mut(sector)
+            .ok_or(SettingsError::TooLarge)?
+            .extend_from_slice(encoded);
+    }
+    for (offset, records) in ANIMATION_SECTORS.into_iter().zip(&sectors) {
+        if settings::read_sector(offset, MAGIC).as_ref() != Some(records) {
+            settings::write_sector(offset, MAGIC, records)?;
+        }
+    }
+    Ok(())
+}
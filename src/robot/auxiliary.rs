@@ -0,0 +1,41 @@
+//! Auxiliary servo enumeration and display helpers.
+//!
+//! Defines the [`AuxServo`] enum for the servos that are not part of a leg (head pan/tilt,
+//! gripper). They are driven independently of the gait engine.
+use crate::robot::commands::ParseCommandError;
+use core::fmt::Display;
+use serde::Deserialize;
+
+pub const AUX_SERVO_COUNT: usize = 3;
+/// Largest angle an auxiliary servo can be set to, in degrees
+pub const AUX_MAX_ANGLE: u8 = 180;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum AuxServo {
+    HeadPan = 0,
+    HeadTilt = 1,
+    Gripper = 2,
+}
+
+impl Display for AuxServo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AuxServo::HeadPan => f.write_str("head pan"),
+            AuxServo::HeadTilt => f.write_str("head tilt"),
+            AuxServo::Gripper => f.write_str("gripper"),
+        }
+    }
+}
+
+impl TryFrom<&str> for AuxServo {
+    type Error = ParseCommandError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "pan" => Ok(AuxServo::HeadPan),
+            "tilt" => Ok(AuxServo::HeadTilt),
+            "grip" => Ok(AuxServo::Gripper),
+            _ => Err(ParseCommandError),
+        }
+    }
+}
@@ -4,17 +4,60 @@
 //! and low-level servo commands, as well as TCP command parsing.
 //!
 //! Used by the network, motion, and servo tasks.
+use crate::config::{KEYFRAME_MAX_MS, VELOCITY_DEADZONE};
+use crate::robot::animations::AnimationName;
+use crate::robot::auxiliary::{AuxServo, AUX_MAX_ANGLE};
+use crate::robot::leg::{Leg, Pose};
+use crate::robot::macros::{MacroName, MacroText};
+use heapless::String;
+use serde::Deserialize;
+
+/// A command from a network client.
+///
+/// The binary protocol encodes the variants by their index: new ones go at the end.
+#[derive(Deserialize)]
 pub enum TcpCommand {
     CloseConnection,
     Test,
     Sit,
     Stand,
+    /// Wave the front right leg
     Wave(u8),
     StepForward(u8),
     StepBackward(u8),
     TurnLeft(u8),
     TurnRight(u8),
     SetAngles([u8; 12]),
+    ClearFault,
+    Status,
+    Aux(AuxServo, u8),
+    /// Walk continuously, see [`Velocity`]
+    Velocity(Velocity),
+    /// Move one foot to a position of its leg frame (mm)
+    Foot(Leg, [f32; 3]),
+    /// Store a macro, see [`crate::robot::macros`]
+    DefineMacro(MacroName, MacroText),
+    DeleteMacro(MacroName),
+    ListMacros,
+    RunMacro(MacroName),
+    /// Stop the macro or the animation running
+    StopMacro,
+    /// Start recording an animation, see [`crate::robot::animations`]
+    RecordAnimation(AnimationName),
+    /// Capture the pose reached as a keyframe, reached in the given time (ms)
+    Keyframe(u16),
+    SaveAnimation,
+    PlayAnimation(AnimationName),
+    DeleteAnimation(AnimationName),
+    ListAnimations,
+    Bow,
+    PushUps(u8),
+    Shimmy(u8),
+    /// Say hello with a front leg
+    Hello(Leg),
+    Dance,
+    /// Wave any leg
+    WaveLeg(Leg, u8),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -27,10 +70,8 @@ impl TryFrom<&str> for TcpCommand {
         let mut tokens = value.trim().split_whitespace();
 
         let cmd = tokens.next().ok_or(ParseCommandError)?;
-        let steps = tokens
-            .next()
-            .map(|s| s.parse::<u8>().unwrap_or(1))
-            .unwrap_or(1);
+        let arg = tokens.next();
+        let steps = arg.map(|s| s.parse::<u8>().unwrap_or(1)).unwrap_or(1);
 
         match cmd {
             "close" => Ok(TcpCommand::CloseConnection),
@@ -42,23 +83,132 @@ impl TryFrom<&str> for TcpCommand {
             "stand" => Ok(TcpCommand::Stand),
             "tl" => Ok(TcpCommand::TurnLeft(steps)),
             "tr" => Ok(TcpCommand::TurnRight(steps)),
+            "clear" => Ok(TcpCommand::ClearFault),
+            "status" => Ok(TcpCommand::Status),
+            "aux" => {
+                let servo = AuxServo::try_from(arg.ok_or(ParseCommandError)?)?;
+                let angle = tokens
+                    .next()
+                    .and_then(|s| s.parse::<u8>().ok())
+                    .filter(|angle| *angle <= AUX_MAX_ANGLE)
+                    .ok_or(ParseCommandError)?;
+                Ok(TcpCommand::Aux(servo, angle))
+            }
+            "vel" => {
+                let forward = parse_f32(arg)?;
+                let turn = parse_f32(tokens.next())?;
+                Ok(TcpCommand::Velocity(Velocity::new(forward, turn)))
+            }
+            "macro" => {
+                let (name, steps) = value.trim()["macro".len()..]
+                    .split_once('=')
+                    .ok_or(ParseCommandError)?;
+                Ok(TcpCommand::DefineMacro(
+                    parse_string(Some(name.trim()))?,
+                    parse_string(Some(steps.trim()))?,
+                ))
+            }
+            "macros" => Ok(TcpCommand::ListMacros),
+            "delete" => Ok(TcpCommand::DeleteMacro(parse_string(arg)?)),
+            "run" => Ok(TcpCommand::RunMacro(parse_string(arg)?)),
+            "stop" => Ok(TcpCommand::StopMacro),
+            "anim" => match arg {
+                Some("record") => Ok(TcpCommand::RecordAnimation(parse_string(tokens.next())?)),
+                Some("key") => {
+                    let ms = tokens
+                        .next()
+                        .and_then(|s| s.parse::<u16>().ok())
+                        .ok_or(ParseCommandError)?;
+                    Ok(TcpCommand::Keyframe(ms.min(KEYFRAME_MAX_MS)))
+                }
+                Some("save") => Ok(TcpCommand::SaveAnimation),
+                Some("play") => Ok(TcpCommand::PlayAnimation(parse_string(tokens.next())?)),
+                Some("delete") => Ok(TcpCommand::DeleteAnimation(parse_string(tokens.next())?)),
+                _ => Err(ParseCommandError),
+            },
+            "anims" => Ok(TcpCommand::ListAnimations),
+            "bow" => Ok(TcpCommand::Bow),
+            "pushups" => Ok(TcpCommand::PushUps(steps)),
+            "shimmy" => Ok(TcpCommand::Shimmy(steps)),
+            "hello" => match Leg::try_from(arg.ok_or(ParseCommandError)?)? {
+                leg @ (Leg::FrontLeft | Leg::FrontRight) => Ok(TcpCommand::Hello(leg)),
+                _ => Err(ParseCommandError),
+            },
+            "dance" => Ok(TcpCommand::Dance),
+            "wave" => {
+                let leg = Leg::try_from(arg.ok_or(ParseCommandError)?)?;
+                let times = match tokens.next() {
+                    Some(s) => s.parse::<u8>().map_err(|_| ParseCommandError)?,
+                    None => 1,
+                };
+                Ok(TcpCommand::WaveLeg(leg, times))
+            }
+            "foot" => {
+                let leg = Leg::try_from(arg.ok_or(ParseCommandError)?)?;
+                let x = parse_f32(tokens.next())?;
+                let y = parse_f32(tokens.next())?;
+                let z = parse_f32(tokens.next())?;
+                Ok(TcpCommand::Foot(leg, [x, y, z]))
+            }
             _ => Err(ParseCommandError),
         }
     }
 }
 
+fn parse_string<const N: usize>(token: Option<&str>) -> Result<String<N>, ParseCommandError> {
+    token
+        .and_then(|s| String::try_from(s).ok())
+        .ok_or(ParseCommandError)
+}
+
+fn parse_f32(token: Option<&str>) -> Result<f32, ParseCommandError> {
+    token
+        .and_then(|s| s.parse::<f32>().ok())
+        .filter(|v| v.is_finite())
+        .ok_or(ParseCommandError)
+}
+
+/// Teleoperation velocity, each component between -1 and 1.
+///
+/// `forward` is positive forward, `turn` positive to the left. The gait task keeps walking in
+/// the dominant direction, faster with a larger magnitude, until the velocity is back to zero.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub struct Velocity {
+    pub forward: f32,
+    pub turn: f32,
+}
+
+impl Velocity {
+    pub const STOP: Self = Self {
+        forward: 0.0,
+        turn: 0.0,
+    };
+
+    pub fn new(forward: f32, turn: f32) -> Self {
+        Self {
+            forward: forward.clamp(-1.0, 1.0),
+            turn: turn.clamp(-1.0, 1.0),
+        }
+    }
+
+    pub fn magnitude(&self) -> f32 {
+        self.forward.abs().max(self.turn.abs())
+    }
+
+    pub fn is_stop(&self) -> bool {
+        self.magnitude() < VELOCITY_DEADZONE
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct ServoCommand {
-    pub current_pos: [[f32; 3]; 4],
-    pub expected_pos: [[f32; 3]; 4],
-    pub temp_speed: [[f32; 3]; 4],
+    pub current_pos: Pose,
+    pub expected_pos: Pose,
+    pub temp_speed: Pose,
 }
 
 impl ServoCommand {
-    pub fn new(
-        current_pos: [[f32; 3]; 4],
-        expected_pos: [[f32; 3]; 4],
-        temp_speed: [[f32; 3]; 4],
-    ) -> Self {
+    pub fn new(current_pos: Pose, expected_pos: Pose, temp_speed: Pose) -> Self {
         Self {
             current_pos,
             expected_pos,
@@ -66,3 +216,15 @@ impl ServoCommand {
         }
     }
 }
+
+/// Angle in degrees for an auxiliary servo, applied right away by the servo task
+pub struct AuxCommand {
+    pub servo: AuxServo,
+    pub angle: u8,
+}
+
+impl AuxCommand {
+    pub fn new(servo: AuxServo, angle: u8) -> Self {
+        Self { servo, angle }
+    }
+}
@@ -3,8 +3,9 @@
 //! Defines the [`Joint`] enum for identifying each joint (coxa, femur, tibia),
 //! and provides display formatting for debugging and logging.
 use core::fmt::Display;
+use serde::Serialize;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Joint {
     Femur = 0,
     Tibia = 1,
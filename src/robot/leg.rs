@@ -1,20 +1,34 @@
 //! Leg enumeration and indexing helpers.
 //!
 //! Provides the [`Leg`] enum for identifying each leg, as well as conversions
-//! between indices and enum variants.
+//! between indices and enum variants. The middle legs only exist with the `hexapod` feature.
 //!
-//! Used for addressing legs in arrays and command structures.
+//! Used for addressing legs in arrays ([`Pose`]) and command structures.
+use crate::robot::commands::ParseCommandError;
 use core::fmt::Display;
 use core::ops::{Index, IndexMut};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Leg {
     FrontLeft = 0,
     BottomLeft = 1,
     FrontRight = 2,
     BottomRight = 3,
+    #[cfg(feature = "hexapod")]
+    MiddleLeft = 4,
+    #[cfg(feature = "hexapod")]
+    MiddleRight = 5,
 }
 
+#[cfg(not(feature = "hexapod"))]
+pub const LEG_COUNT: usize = 4;
+#[cfg(feature = "hexapod")]
+pub const LEG_COUNT: usize = 6;
+
+/// Coordinates of the end of every leg, each in its own leg frame
+pub type Pose = [[f32; 3]; LEG_COUNT];
+
 impl Display for Leg {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -22,6 +36,10 @@ impl Display for Leg {
             Leg::FrontRight => f.write_str("Front right"),
             Leg::BottomLeft => f.write_str("Bottom left"),
             Leg::BottomRight => f.write_str("Bottom right"),
+            #[cfg(feature = "hexapod")]
+            Leg::MiddleLeft => f.write_str("Middle left"),
+            #[cfg(feature = "hexapod")]
+            Leg::MiddleRight => f.write_str("Middle right"),
         }
     }
 }
@@ -33,12 +51,35 @@ impl From<usize> for Leg {
             1 => Leg::BottomLeft,
             2 => Leg::FrontRight,
             3 => Leg::BottomRight,
+            #[cfg(feature = "hexapod")]
+            4 => Leg::MiddleLeft,
+            #[cfg(feature = "hexapod")]
+            5 => Leg::MiddleRight,
             _ => unreachable!(),
         }
     }
 }
 
-impl Index<Leg> for [[f32; 3]; 4] {
+/// Short names used in commands: `fl`, `bl`, `fr`, `br` (and `ml`, `mr`)
+impl TryFrom<&str> for Leg {
+    type Error = ParseCommandError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "fl" => Ok(Leg::FrontLeft),
+            "bl" => Ok(Leg::BottomLeft),
+            "fr" => Ok(Leg::FrontRight),
+            "br" => Ok(Leg::BottomRight),
+            #[cfg(feature = "hexapod")]
+            "ml" => Ok(Leg::MiddleLeft),
+            #[cfg(feature = "hexapod")]
+            "mr" => Ok(Leg::MiddleRight),
+            _ => Err(ParseCommandError),
+        }
+    }
+}
+
+impl Index<Leg> for Pose {
     type Output = [f32; 3];
 
     fn index(&self, leg: Leg) -> &Self::Output {
@@ -46,7 +87,7 @@ impl Index<Leg> for [[f32; 3]; 4] {
     }
 }
 
-impl IndexMut<Leg> for [[f32; 3]; 4] {
+impl IndexMut<Leg> for Pose {
     fn index_mut(&mut self, leg: Leg) -> &mut Self::Output {
         &mut self[leg as usize]
     }
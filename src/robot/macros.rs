@@ -0,0 +1,217 @@
+//! Named command sequences stored on the robot.
+//!
+//! A macro is a list of steps separated by `;`, each step being a motion command of the TCP
+//! grammar, `wait <ms>`, or a loop: `repeat <n> { ... }` runs its steps `n` times, `repeat
+//! { ... }` until the macro is stopped. For example:
+//!
+//! `stand; repeat 3 { w 1; wait 500 }; sit`
+//!
+//! Animations are motions too, `anim play <name>` being a valid step.
+//!
+//! Macros are stored in the settings as text and compiled into a [`Program`] when run, by the
+//! gait task. The built-in ones can't be redefined or deleted.
+extern crate alloc;
+
+use crate::config::{MACRO_COUNT, MACRO_NAME_SIZE, MACRO_SIZE};
+use crate::robot::commands::TcpCommand;
+use crate::settings::{self, SettingsError};
+use alloc::vec::Vec;
+use core::fmt::Display;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use heapless::String;
+use serde::Serialize;
+
+pub type MacroName = String<MACRO_NAME_SIZE>;
+pub type MacroText = String<MACRO_SIZE>;
+
+/// Stops the macro or the animation running, in the middle of a motion or during a wait
+pub static STOP_MACRO: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Macros of the firmware, `test` being the former demo sequence
+const BUILT_IN: &[(&str, &str)] = &[(
+    "test",
+    "stand; wait 2000; w 2; wait 2000; sf 2; wait 2000; sit; wait 5000",
+)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MacroError {
+    /// The name isn't made of letters, digits, `-` and `_`
+    InvalidName,
+    /// The steps don't follow the macro grammar
+    Syntax,
+    Unknown,
+    BuiltIn,
+    /// [`MACRO_COUNT`] macros are stored already
+    Full,
+    Settings(SettingsError),
+}
+
+impl Display for MacroError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MacroError::InvalidName => f.write_str("invalid macro name"),
+            MacroError::Syntax => f.write_str("invalid macro steps"),
+            MacroError::Unknown => f.write_str("unknown macro"),
+            MacroError::BuiltIn => f.write_str("built-in macro"),
+            MacroError::Full => write!(f, "no room for more than {MACRO_COUNT} macros"),
+            MacroError::Settings(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A step of a compiled macro
+pub enum Op {
+    Command(TcpCommand),
+    Wait(u32),
+    /// Start of a loop, running forever without a count. `end` is the index of its [`Op::End`].
+    Repeat {
+        count: Option<u16>,
+        end: usize,
+    },
+    /// End of the loop started at `start`
+    End {
+        start: usize,
+    },
+}
+
+pub type Program = Vec<Op>;
+
+/// Compile the steps of a macro
+pub fn compile(text: &str) -> Result<Program, MacroError> {
+    let mut program = Vec::new();
+    // loops not closed yet
+    let mut open = Vec::new();
+    let mut rest = text;
+    loop {
+        let end = rest.find([';', '{', '}']).unwrap_or(rest.len());
+        let step = rest[..end].trim();
+        let separator = rest[end..].chars().next();
+
+        if separator == Some('{') {
+            let count = match step.strip_prefix("repeat").map(str::trim) {
+                Some("") => None,
+                Some(count) => Some(count.parse().map_err(|_| MacroError::Syntax)?),
+                None => return Err(MacroError::Syntax),
+            };
+            open.push(program.len());
+            program.push(Op::Repeat { count, end: 0 });
+        } else if !step.is_empty() {
+            program.push(compile_step(step)?);
+        }
+
+        if separator == Some('}') {
+            let start = open.pop().ok_or(MacroError::Syntax)?;
+            let end = program.len();
+            if let Op::Repeat { end: loop_end, .. } = &mut program[start] {
+                *loop_end = end;
+            }
+            program.push(Op::End { start });
+        }
+        match separator {
+            Some(_) => rest = &rest[end + 1..],
+            None => break,
+        }
+    }
+    if !open.is_empty() {
+        return Err(MacroError::Syntax);
+    }
+    Ok(program)
+}
+
+fn compile_step(step: &str) -> Result<Op, MacroError> {
+    if let Some(ms) = step.strip_prefix("wait ") {
+        return ms
+            .trim()
+            .parse()
+            .map(Op::Wait)
+            .map_err(|_| MacroError::Syntax);
+    }
+    match TcpCommand::try_from(step) {
+        Ok(
+            cmd @ (TcpCommand::Sit
+            | TcpCommand::Stand
+            | TcpCommand::Wave(_)
+            | TcpCommand::WaveLeg(..)
+            | TcpCommand::StepForward(_)
+            | TcpCommand::StepBackward(_)
+            | TcpCommand::TurnLeft(_)
+            | TcpCommand::TurnRight(_)
+            | TcpCommand::Foot(..)
+            | TcpCommand::PlayAnimation(_)
+            | TcpCommand::Bow
+            | TcpCommand::PushUps(_)
+            | TcpCommand::Shimmy(_)
+            | TcpCommand::Hello(_)
+            | TcpCommand::Dance
+            | TcpCommand::ClearFault),
+        ) => Ok(Op::Command(cmd)),
+        // other commands aren't motions, or would run a macro from a macro
+        _ => Err(MacroError::Syntax),
+    }
+}
+
+/// Names of macros and animations, letters, digits, `-` and `_`
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Store a macro, replacing the one of the same name
+pub async fn define(name: &str, text: &str) -> Result<(), MacroError> {
+    if !valid_name(name) {
+        return Err(MacroError::InvalidName);
+    }
+    if BUILT_IN.iter().any(|(built_in, _)| *built_in == name) {
+        return Err(MacroError::BuiltIn);
+    }
+    compile(text)?;
+    let name = MacroName::try_from(name).map_err(|_| MacroError::InvalidName)?;
+    let text = MacroText::try_from(text.trim()).map_err(|_| MacroError::Syntax)?;
+
+    let stored = settings::load().macros;
+    if stored.len() >= MACRO_COUNT && !stored.iter().any(|(n, _)| *n == name) {
+        return Err(MacroError::Full);
+    }
+    settings::update(|s| {
+        s.macros.retain(|(n, _)| *n != name);
+        s.macros.push((name, text));
+    })
+    .await
+    .map_err(MacroError::Settings)
+}
+
+pub async fn delete(name: &str) -> Result<(), MacroError> {
+    if BUILT_IN.iter().any(|(built_in, _)| *built_in == name) {
+        return Err(MacroError::BuiltIn);
+    }
+    if !settings::load().macros.iter().any(|(n, _)| n == name) {
+        return Err(MacroError::Unknown);
+    }
+    settings::update(|s| s.macros.retain(|(n, _)| n != name))
+        .await
+        .map_err(MacroError::Settings)
+}
+
+/// Names of the built-in then of the stored macros
+pub fn list() -> Vec<MacroName> {
+    let built_in = BUILT_IN
+        .iter()
+        .filter_map(|(name, _)| MacroName::try_from(*name).ok());
+    let stored = settings::load().macros.into_iter().map(|(name, _)| name);
+    built_in.chain(stored).collect()
+}
+
+/// Steps of a macro
+pub fn find(name: &str) -> Result<MacroText, MacroError> {
+    if let Some((_, text)) = BUILT_IN.iter().find(|(built_in, _)| *built_in == name) {
+        return MacroText::try_from(*text).map_err(|_| MacroError::Syntax);
+    }
+    settings::load()
+        .macros
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, text)| text)
+        .ok_or(MacroError::Unknown)
+}
@@ -1,12 +1,22 @@
 //! Core robot types and configuration.
 //!
 //! This module defines the main types and constants for Spiderbot, including:
+//! - [`animations`]: Keyframe animations recorded and stored on the robot.
+//! - [`auxiliary`]: Auxiliary (non-leg) servo enumeration.
 //! - [`commands`]: Command types for inter-task communication (TCP and servo).
 //! - [`config`]: Physical and movement constants for the robot.
 //! - [`leg`]: Leg enumeration and indexing helpers.
+//! - [`macros`]: Named command sequences stored on the robot.
 //! - [`joint`]: Joint enumeration and display helpers.
+//! - [`state`]: Gait pose, busy flag and speeds shared between tasks.
+//! - [`status`]: Driver and fault status shared between tasks.
 //!
 //! These types are used throughout the firmware for movement, configuration, and control.
+pub mod animations;
+pub mod auxiliary;
 pub mod commands;
 pub mod joint;
 pub mod leg;
+pub mod macros;
+pub mod state;
+pub mod status;
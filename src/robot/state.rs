@@ -0,0 +1,59 @@
+//! Gait state and speeds shared between tasks.
+//!
+//! The gait task publishes the pose reached by the gait engine and whether it is executing a
+//! command. The speeds are read by the gait task before each command, so network clients can
+//! tune them while the robot runs.
+use crate::config::Speeds;
+use crate::robot::leg::{Pose, LEG_COUNT};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+
+static GAIT_STATE: BlockingMutex<CriticalSectionRawMutex, Cell<GaitState>> =
+    BlockingMutex::new(Cell::new(GaitState::new()));
+
+static SPEEDS: BlockingMutex<CriticalSectionRawMutex, Cell<Speeds>> =
+    BlockingMutex::new(Cell::new(Speeds::DEFAULT));
+
+#[derive(Debug, Clone, Copy)]
+pub struct GaitState {
+    /// Last pose reached by the gait engine
+    pub pose: Pose,
+    /// A command is being executed
+    pub busy: bool,
+}
+
+impl GaitState {
+    pub const fn new() -> Self {
+        Self {
+            pose: [[0.0; 3]; LEG_COUNT],
+            busy: false,
+        }
+    }
+}
+
+impl Default for GaitState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn gait_state() -> GaitState {
+    GAIT_STATE.lock(|state| state.get())
+}
+
+pub fn update_gait_state(f: impl FnOnce(&mut GaitState)) {
+    GAIT_STATE.lock(|state| {
+        let mut new_state = state.get();
+        f(&mut new_state);
+        state.set(new_state);
+    });
+}
+
+/// Speeds applied to the next gait command
+pub fn speeds() -> Speeds {
+    SPEEDS.lock(|speeds| speeds.get())
+}
+
+pub fn set_speeds(new_speeds: Speeds) {
+    SPEEDS.lock(|speeds| speeds.set(new_speeds));
+}
@@ -0,0 +1,95 @@
+//! Robot status shared between tasks.
+//!
+//! The servo task reports whether the servo driver is usable and the gait task reports
+//! movement faults, and the keyframes or saves of an animation it couldn't do. Network clients
+//! subscribe to the [`ROBOT_STATUS`] watch to relay them.
+use crate::config::STATUS_RECEIVERS;
+use crate::kinematics::gait_engine::MotionError;
+use crate::robot::animations::AnimationError;
+use core::fmt::Display;
+use core::future::pending;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
+use serde::Serialize;
+
+pub static ROBOT_STATUS: Watch<CriticalSectionRawMutex, RobotStatus, STATUS_RECEIVERS> =
+    Watch::new_with(RobotStatus::new());
+
+pub type StatusReceiver = Receiver<'static, CriticalSectionRawMutex, RobotStatus, STATUS_RECEIVERS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DriverState {
+    Ready,
+    /// The PCA9685 doesn't answer, movements are rejected until it comes back
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RobotStatus {
+    pub driver: DriverState,
+    pub fault: Option<MotionError>,
+    /// Why the last keyframe or save queued failed, until the next one succeeds
+    pub animation: Option<AnimationError>,
+}
+
+impl RobotStatus {
+    pub const fn new() -> Self {
+        Self {
+            driver: DriverState::Ready,
+            fault: None,
+            animation: None,
+        }
+    }
+}
+
+impl Default for RobotStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for RobotStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.driver {
+            DriverState::Ready => f.write_str("driver ready")?,
+            DriverState::Missing => f.write_str("driver missing")?,
+        }
+        match self.fault {
+            Some(fault) => write!(f, ", fault: {fault}")?,
+            None => f.write_str(", no fault")?,
+        }
+        match self.animation {
+            Some(e) => write!(f, ", animation: {e}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Current status of the robot
+pub fn robot_status() -> RobotStatus {
+    ROBOT_STATUS.try_get().unwrap_or_default()
+}
+
+/// Modify the status, notifying the subscribers only if it actually changed
+pub fn update_status(f: impl Fn(&mut RobotStatus)) {
+    ROBOT_STATUS
+        .sender()
+        .send_if_modified(|status| match status {
+            Some(status) => {
+                let previous = *status;
+                f(status);
+                previous != *status
+            }
+            None => false,
+        });
+}
+
+/// Wait for the next status change, forever if no receiver could be allocated
+pub async fn status_changed(receiver: &mut Option<StatusReceiver>) -> RobotStatus {
+    match receiver {
+        Some(receiver) => receiver.changed().await,
+        None => pending().await,
+    }
+}
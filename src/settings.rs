@@ -0,0 +1,211 @@
+//! Settings persisted in flash.
+//!
+//! The settings live in the first sector of the `nvs` partition (see `partitions.csv`). They
+//! are stored as tagged records, so fields can be added without losing the ones
+//! already stored:
+//!
+//! `magic (4) | length of the records (2) | records | checksum of the records (4)`
+//!
+//! each record being `tag (1) | length (1) | data`. Unknown tags are skipped.
+//!
+//! The other sectors of the partition hold data too large for a record, in the same layout
+//! with their own magic and records (see [`read_sector`] and [`write_sector`]).
+extern crate alloc;
+
+use crate::config::{BROKER_SIZE, HOSTNAME_SIZE, MACRO_NAME_SIZE, MACRO_SIZE, SECRET_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use heapless::String;
+use log::{info, warn};
+use serde::Serialize;
+
+/// Start of the `nvs` partition
+const SETTINGS_OFFSET: u32 = 0x9000;
+/// A flash sector
+pub(crate) const SECTOR_SIZE: usize = 4096;
+const MAGIC: [u8; 4] = *b"SPDR";
+const HEADER_SIZE: usize = 6;
+const CHECKSUM_SIZE: usize = 4;
+/// Room left for the records in a sector
+pub(crate) const RECORDS_SIZE: usize = SECTOR_SIZE - HEADER_SIZE - CHECKSUM_SIZE;
+
+const TAG_WIFI_SSID: u8 = 1;
+const TAG_WIFI_PASSWORD: u8 = 2;
+const TAG_HOSTNAME: u8 = 3;
+const TAG_MQTT_BROKER: u8 = 4;
+const TAG_AUTH_SECRET: u8 = 5;
+/// One record per macro, `name=steps`
+const TAG_MACRO: u8 = 6;
+
+/// Serializes the updates, each one reading then writing the whole sector
+static SETTINGS_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SettingsError {
+    Flash,
+    /// The records don't fit in their sector
+    TooLarge,
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SettingsError::Flash => f.write_str("flash access failed"),
+            SettingsError::TooLarge => f.write_str("not enough room in flash"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiCredentials {
+    pub ssid: String<32>,
+    pub password: String<64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Network joined in station mode, provisioned over Bluetooth
+    pub wifi: Option<WifiCredentials>,
+    /// Name announced over mDNS, `spiderbot-<mac>` when unset
+    pub hostname: Option<String<HOSTNAME_SIZE>>,
+    /// MQTT broker as `host[:port]`, no MQTT client when unset
+    pub mqtt_broker: Option<String<BROKER_SIZE>>,
+    /// Shared secret of the remote clients, no authentication when unset
+    pub auth_secret: Option<String<SECRET_SIZE>>,
+    /// Command sequences by name, see [`crate::robot::macros`]
+    pub macros: Vec<(String<MACRO_NAME_SIZE>, String<MACRO_SIZE>)>,
+}
+
+impl Settings {
+    fn encode(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(wifi) = &self.wifi {
+            push_record(&mut records, TAG_WIFI_SSID, wifi.ssid.as_bytes());
+            push_record(&mut records, TAG_WIFI_PASSWORD, wifi.password.as_bytes());
+        }
+        if let Some(hostname) = &self.hostname {
+            push_record(&mut records, TAG_HOSTNAME, hostname.as_bytes());
+        }
+        if let Some(broker) = &self.mqtt_broker {
+            push_record(&mut records, TAG_MQTT_BROKER, broker.as_bytes());
+        }
+        if let Some(secret) = &self.auth_secret {
+            push_record(&mut records, TAG_AUTH_SECRET, secret.as_bytes());
+        }
+        for (name, steps) in &self.macros {
+            let record = [name.as_bytes(), b"=", steps.as_bytes()].concat();
+            push_record(&mut records, TAG_MACRO, &record);
+        }
+        records
+    }
+
+    /// `None` if the records aren't valid settings
+    fn decode(records: &[u8]) -> Option<Self> {
+        let mut settings = Settings::default();
+        let (mut ssid, mut password) = (None, None);
+        let mut rest = records;
+        while let [tag, len, tail @ ..] = rest {
+            let value = tail.get(..*len as usize)?;
+            let text = core::str::from_utf8(value).ok();
+            match *tag {
+                TAG_WIFI_SSID => ssid = text.and_then(|s| String::try_from(s).ok()),
+                TAG_WIFI_PASSWORD => password = text.and_then(|s| String::try_from(s).ok()),
+                TAG_HOSTNAME => settings.hostname = text.and_then(|s| String::try_from(s).ok()),
+                TAG_MQTT_BROKER => {
+                    settings.mqtt_broker = text.and_then(|s| String::try_from(s).ok())
+                }
+                TAG_AUTH_SECRET => {
+                    settings.auth_secret = text.and_then(|s| String::try_from(s).ok())
+                }
+                TAG_MACRO => {
+                    let entry = text
+                        .and_then(|s| s.split_once('='))
+                        .and_then(|(name, steps)| {
+                            Some((String::try_from(name).ok()?, String::try_from(steps).ok()?))
+                        });
+                    settings.macros.extend(entry);
+                }
+                _ => {} // written by a newer firmware
+            }
+            rest = &tail[*len as usize..];
+        }
+        if let (Some(ssid), Some(password)) = (ssid, password) {
+            settings.wifi = Some(WifiCredentials { ssid, password });
+        }
+        Some(settings)
+    }
+}
+
+fn push_record(records: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    records.push(tag);
+    records.push(value.len() as u8);
+    records.extend_from_slice(value);
+}
+
+/// FNV-1a, enough to tell a written sector from an erased or torn one
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Records of the sector at `offset`, `None` if it doesn't hold valid ones
+pub(crate) fn read_sector(offset: u32, magic: [u8; 4]) -> Option<Vec<u8>> {
+    let mut data = vec![0u8; SECTOR_SIZE];
+    if let Err(e) = FlashStorage::new().read(offset, &mut data) {
+        warn!("Fail reading the sector at {:#x}: {:?}", offset, e);
+        return None;
+    }
+    if data[..4] != magic {
+        return None;
+    }
+    let len = u16::from_le_bytes([data[4], data[5]]) as usize;
+    let records = data.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    let stored = data.get(HEADER_SIZE + len..HEADER_SIZE + len + CHECKSUM_SIZE)?;
+    if checksum(records).to_le_bytes() != stored {
+        return None;
+    }
+    Some(records.to_vec())
+}
+
+/// Replace the records of the sector at `offset`
+pub(crate) fn write_sector(
+    offset: u32,
+    magic: [u8; 4],
+    records: &[u8],
+) -> Result<(), SettingsError> {
+    if records.len() > RECORDS_SIZE {
+        return Err(SettingsError::TooLarge);
+    }
+    let mut data = Vec::with_capacity(HEADER_SIZE + records.len() + CHECKSUM_SIZE);
+    data.extend_from_slice(&magic);
+    data.extend_from_slice(&(records.len() as u16).to_le_bytes());
+    data.extend_from_slice(records);
+    data.extend_from_slice(&checksum(records).to_le_bytes());
+    FlashStorage::new().write(offset, &data).map_err(|e| {
+        warn!("Fail writing the sector at {:#x}: {:?}", offset, e);
+        SettingsError::Flash
+    })
+}
+
+/// Settings stored in flash, the defaults if there are none
+pub fn load() -> Settings {
+    read_sector(SETTINGS_OFFSET, MAGIC)
+        .and_then(|records| Settings::decode(&records))
+        .unwrap_or_else(|| {
+            info!("No settings stored, using the defaults");
+            Settings::default()
+        })
+}
+
+/// Modify the stored settings
+pub async fn update(f: impl FnOnce(&mut Settings)) -> Result<(), SettingsError> {
+    let _lock = SETTINGS_LOCK.lock().await;
+    let mut settings = load();
+    f(&mut settings);
+    write_sector(SETTINGS_OFFSET, MAGIC, &settings.encode())
+}
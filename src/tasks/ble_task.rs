@@ -0,0 +1,406 @@
+//! Bluetooth LE control task.
+//!
+//! Exposes a GATT service so the robot can be driven from a phone without any Wi-Fi network:
+//! - command (write): a command in the TCP grammar, as UTF-8 text, going through the same
+//!   dispatch as the network interfaces. The longest ones, macro definitions, need the phone to
+//!   negotiate a larger MTU
+//! - status (read, notify): the robot status as text, notified on every change
+//! - state (read, notify): battery level and pose, notified every [`BLE_STATE_PERIOD_MS`]
+//! - challenge (read, notify): the challenge to answer when a secret is set, empty once the
+//!   phone authenticated
+//!
+//! When a secret is set, the phone writes `auth <hmac hex>` to the command characteristic
+//! before any command but `status` (see [`crate::auth`]); a wrong answer notifies a new
+//! challenge. The refused commands are dropped.
+//!
+//! A second service provisions the Wi-Fi credentials (see `wifi_task`), also reserved to an
+//! authenticated phone when a secret is set:
+//! - ssid (write) and password (write): UTF-8 text, up to 32 and 64 bytes
+//! - connect (write): any value tries the credentials written, the robot joins the network and
+//!   stores them once connected
+//! - result (read, notify): [`ProvisioningState`] as a byte, 0 idle, 1 connecting, 2 connected,
+//!   3 failed, 4 connected but not stored
+//!
+//! The state value is `[battery %, busy, x, y, z, ...]`: the battery level is 255 on boards
+//! without battery sensing (every current board revision), then each foot position of the pose
+//! as i16 little endian in tenths of mm, in leg order.
+//!
+//! A single phone is served at a time, advertising resumes when it disconnects.
+use crate::auth::{Session, CHALLENGE_HEX_SIZE};
+use crate::config::{AUXCMD_CHANNEL_SIZE, BLE_STATE_PERIOD_MS, TCPCMD_CHANNEL_SIZE};
+use crate::config::{MACRO_NAME_SIZE, MACRO_SIZE};
+use crate::robot::commands::{AuxCommand, TcpCommand};
+use crate::robot::leg::LEG_COUNT;
+use crate::robot::state::gait_state;
+use crate::robot::status::{robot_status, status_changed, RobotStatus, ROBOT_STATUS};
+use crate::settings::WifiCredentials;
+use crate::tasks::net_task::{dispatch, Dispatched};
+use crate::tasks::wifi_task::{ProvisioningState, PROVISIONING, PROVISIONING_RESULT};
+use alloc::format;
+use bt_hci::controller::ExternalController;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{Duration, Ticker};
+use esp_hal::efuse::Efuse;
+use esp_wifi::ble::controller::BleConnector;
+use heapless::{String, Vec};
+use log::{error, info, warn};
+use trouble_host::prelude::*;
+
+/// Command slots of the HCI controller
+const BLE_SLOTS: usize = 20;
+const CONNECTIONS_MAX: usize = 1;
+/// Signal and ATT channels
+const L2CAP_CHANNELS_MAX: usize = 2;
+const L2CAP_MTU: usize = 255;
+
+const BLE_NAME: &str = "Spiderbot";
+/// The longest command, `macro <name> = <steps>`
+const COMMAND_SIZE: usize = "macro ".len() + MACRO_NAME_SIZE + " = ".len() + MACRO_SIZE;
+const STATUS_SIZE: usize = 64;
+const STATE_SIZE: usize = 2 + LEG_COUNT * 3 * 2;
+const SSID_SIZE: usize = 32;
+const PASSWORD_SIZE: usize = 64;
+/// Battery level reported without battery sensing
+const BATTERY_UNKNOWN: u8 = 255;
+
+// the L2CAP header takes 4 bytes of the MTU, the ATT write header 3
+const _: () = assert!(
+    COMMAND_SIZE <= L2CAP_MTU - 4 - 3,
+    "A command written over BLE doesn't fit in an ATT write"
+);
+
+pub type BleController = ExternalController<BleConnector<'static>, BLE_SLOTS>;
+
+#[gatt_server]
+struct Server {
+    control: ControlService,
+    provisioning: ProvisioningService,
+}
+
+#[gatt_service(uuid = "c0de0001-5b1d-4e2a-9f3b-0a5b1de4b07d")]
+struct ControlService {
+    #[characteristic(uuid = "c0de0002-5b1d-4e2a-9f3b-0a5b1de4b07d", write)]
+    command: Vec<u8, COMMAND_SIZE>,
+    #[characteristic(uuid = "c0de0003-5b1d-4e2a-9f3b-0a5b1de4b07d", read, notify)]
+    status: Vec<u8, STATUS_SIZE>,
+    #[characteristic(uuid = "c0de0004-5b1d-4e2a-9f3b-0a5b1de4b07d", read, notify)]
+    state: [u8; STATE_SIZE],
+    #[characteristic(uuid = "c0de0005-5b1d-4e2a-9f3b-0a5b1de4b07d", read, notify)]
+    challenge: Vec<u8, CHALLENGE_HEX_SIZE>,
+}
+
+#[gatt_service(uuid = "c0de0101-5b1d-4e2a-9f3b-0a5b1de4b07d")]
+struct ProvisioningService {
+    #[characteristic(uuid = "c0de0102-5b1d-4e2a-9f3b-0a5b1de4b07d", write)]
+    ssid: Vec<u8, SSID_SIZE>,
+    #[characteristic(uuid = "c0de0103-5b1d-4e2a-9f3b-0a5b1de4b07d", write)]
+    password: Vec<u8, PASSWORD_SIZE>,
+    #[characteristic(uuid = "c0de0104-5b1d-4e2a-9f3b-0a5b1de4b07d", write)]
+    connect: u8,
+    #[characteristic(uuid = "c0de0105-5b1d-4e2a-9f3b-0a5b1de4b07d", read, notify)]
+    result: u8,
+}
+
+#[embassy_executor::task]
+pub async fn ble_task(
+    controller: BleController,
+    cmd_sender: Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) {
+    // static random address derived from the MAC, the two upper bits must be set
+    let mut address = Efuse::mac_address();
+    address.reverse();
+    address[5] |= 0xc0;
+
+    let mut resources: HostResources<CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU> =
+        HostResources::new();
+    let stack =
+        trouble_host::new(controller, &mut resources).set_random_address(Address::random(address));
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+
+    let server = match Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: BLE_NAME,
+        appearance: &appearance::UNKNOWN,
+    })) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Fail creating the GATT server: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = server.set(
+        &server.provisioning.result,
+        &(ProvisioningState::Idle as u8),
+    ) {
+        warn!("Fail setting the provisioning result: {:?}", e);
+    }
+
+    let host = async {
+        loop {
+            if let Err(e) = runner.run().await {
+                error!("BLE host error: {:?}", e);
+            }
+        }
+    };
+
+    let connections = async {
+        info!("BLE advertising as {BLE_NAME}");
+        loop {
+            match advertise(&mut peripheral, &server).await {
+                Ok(conn) => {
+                    info!("BLE client connected");
+                    select(
+                        gatt_events(&server, &conn, &cmd_sender, &aux_sender),
+                        notifications(&server, &conn),
+                    )
+                    .await;
+                    info!("BLE client disconnected");
+                }
+                Err(e) => error!("BLE advertising error: {:?}", e),
+            }
+        }
+    };
+
+    join(host, connections).await;
+}
+
+async fn advertise<'values, 'server, C: Controller>(
+    peripheral: &mut Peripheral<'values, C>,
+    server: &'server Server<'values>,
+) -> Result<GattConnection<'values, 'server>, BleHostError<C::Error>> {
+    let mut adv_data = [0; 31];
+    let len = AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::CompleteLocalName(BLE_NAME.as_bytes()),
+        ],
+        &mut adv_data[..],
+    )?;
+    let advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..len],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    let conn = advertiser.accept().await?.with_attribute_server(server)?;
+    Ok(conn)
+}
+
+/// What the phone wrote to the command characteristic
+enum Request {
+    Auth(String<COMMAND_SIZE>),
+    Command(TcpCommand),
+}
+
+/// Handle writes to the command and provisioning characteristics until the phone disconnects
+async fn gatt_events(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_>,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) {
+    let command = server.control.command;
+    let provisioning = &server.provisioning;
+    let mut session = Session::new();
+    if let Err(e) = server.set(&server.control.challenge, &challenge_value(&session)) {
+        warn!("Fail setting the BLE challenge: {:?}", e);
+    }
+    // credentials written by this phone, tried on a write to connect
+    let mut ssid: String<SSID_SIZE> = String::new();
+    let mut password: String<PASSWORD_SIZE> = String::new();
+    loop {
+        match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => {
+                info!("BLE disconnected: {:?}", reason);
+                break;
+            }
+            GattConnectionEvent::Gatt { event } => match event {
+                Ok(event) => {
+                    let request = match &event {
+                        GattEvent::Write(write) if write.handle() == command.handle => {
+                            parse_request(write.data())
+                        }
+                        GattEvent::Write(write)
+                            if !session.authenticated()
+                                && [
+                                    provisioning.ssid.handle,
+                                    provisioning.password.handle,
+                                    provisioning.connect.handle,
+                                ]
+                                .contains(&write.handle()) =>
+                        {
+                            warn!("BLE provisioning refused, authentication required");
+                            None
+                        }
+                        GattEvent::Write(write) if write.handle() == provisioning.ssid.handle => {
+                            ssid = text_value(write.data());
+                            None
+                        }
+                        GattEvent::Write(write)
+                            if write.handle() == provisioning.password.handle =>
+                        {
+                            password = text_value(write.data());
+                            None
+                        }
+                        GattEvent::Write(write)
+                            if write.handle() == provisioning.connect.handle =>
+                        {
+                            if ssid.is_empty() {
+                                warn!("BLE provisioning without an SSID");
+                            } else {
+                                PROVISIONING.signal(WifiCredentials {
+                                    ssid: ssid.clone(),
+                                    password: password.clone(),
+                                });
+                            }
+                            None
+                        }
+                        _ => None,
+                    };
+                    // the write is acknowledged before the command may wait for room in a queue
+                    match event.accept() {
+                        Ok(reply) => reply.send().await,
+                        Err(e) => warn!("BLE reply error: {:?}", e),
+                    }
+
+                    match request {
+                        Some(Request::Auth(response)) => {
+                            if session.authenticate(&response, None) {
+                                info!("BLE client authenticated");
+                            } else {
+                                warn!("BLE authentication failed");
+                            }
+                            notify_challenge(server, conn, &session).await;
+                        }
+                        Some(Request::Command(cmd)) if !session.allows(&cmd) => {
+                            warn!("BLE command refused, authentication required");
+                        }
+                        Some(Request::Command(cmd)) => {
+                            if let Dispatched::Status(status) =
+                                dispatch(cmd, cmd_sender, aux_sender).await
+                            {
+                                notify_status(server, conn, status).await;
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                Err(e) => warn!("BLE GATT error: {:?}", e),
+            },
+            _ => {}
+        }
+    }
+}
+
+fn parse_request(data: &[u8]) -> Option<Request> {
+    let text = core::str::from_utf8(data).ok()?;
+    match text.strip_prefix("auth ") {
+        Some(response) => String::try_from(response.trim()).ok().map(Request::Auth),
+        None => TcpCommand::try_from(text).ok().map(Request::Command),
+    }
+}
+
+/// Characteristic value as text, empty if it isn't valid UTF-8
+fn text_value<const N: usize>(data: &[u8]) -> String<N> {
+    core::str::from_utf8(data)
+        .ok()
+        .and_then(|text| String::try_from(text).ok())
+        .unwrap_or_default()
+}
+
+/// Push the status on every change, the provisioning progress and the state periodically
+async fn notifications(server: &Server<'_>, conn: &GattConnection<'_, '_>) {
+    let mut status_receiver = ROBOT_STATUS.receiver();
+    if status_receiver.is_none() {
+        warn!("Too many status subscribers, BLE status won't be notified");
+    }
+    let mut ticker = Ticker::every(Duration::from_millis(BLE_STATE_PERIOD_MS));
+    notify_status(server, conn, robot_status()).await;
+
+    loop {
+        match select3(
+            status_changed(&mut status_receiver),
+            PROVISIONING_RESULT.wait(),
+            ticker.next(),
+        )
+        .await
+        {
+            Either3::First(status) => notify_status(server, conn, status).await,
+            Either3::Second(state) => notify_provisioning(server, conn, state).await,
+            Either3::Third(()) => {
+                if server
+                    .control
+                    .state
+                    .notify(conn, &state_value())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn notify_status(server: &Server<'_>, conn: &GattConnection<'_, '_>, status: RobotStatus) {
+    let text = format!("{status}");
+    let len = text.len().min(STATUS_SIZE);
+    let value = Vec::from_slice(&text.as_bytes()[..len]).unwrap_or_default();
+    if let Err(e) = server.control.status.notify(conn, &value).await {
+        warn!("BLE status notification error: {:?}", e);
+    }
+}
+
+/// Challenge left to answer, empty once authenticated
+fn challenge_value(session: &Session) -> Vec<u8, CHALLENGE_HEX_SIZE> {
+    let challenge = session.challenge().unwrap_or_default();
+    Vec::from_slice(challenge.as_bytes()).unwrap_or_default()
+}
+
+async fn notify_challenge(server: &Server<'_>, conn: &GattConnection<'_, '_>, session: &Session) {
+    if let Err(e) = server
+        .control
+        .challenge
+        .notify(conn, &challenge_value(session))
+        .await
+    {
+        warn!("BLE challenge notification error: {:?}", e);
+    }
+}
+
+async fn notify_provisioning(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_>,
+    state: ProvisioningState,
+) {
+    if let Err(e) = server
+        .provisioning
+        .result
+        .notify(conn, &(state as u8))
+        .await
+    {
+        warn!("BLE provisioning notification error: {:?}", e);
+    }
+}
+
+fn state_value() -> [u8; STATE_SIZE] {
+    let state = gait_state();
+    let mut value = [0u8; STATE_SIZE];
+    value[0] = BATTERY_UNKNOWN;
+    value[1] = state.busy as u8;
+    for (i, coordinate) in state.pose.iter().flatten().enumerate() {
+        let tenths = (coordinate * 10.0) as i16;
+        value[2 + 2 * i..4 + 2 * i].copy_from_slice(&tenths.to_le_bytes());
+    }
+    value
+}
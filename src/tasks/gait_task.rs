@@ -4,14 +4,38 @@
 //! using the gait engine and kinematics modules.
 //!
 //! Communicates with the servo task to execute planned movements.
-use crate::kinematics::gait_engine::GaitEngine;
-use crate::robot::commands::{ServoCommand, TcpCommand};
+//!
+//! Teleoperation velocities don't go through the command queue: the latest one is picked from
+//! [`TELEOP_VELOCITY`] after every gait cycle, and queued commands take over the walk.
+//!
+//! Macros run here step by step; queued commands wait for their end, [`STOP_MACRO`] stops them.
+//! A motion interrupted by a stop is given up on where the legs are, then the robot stands.
+//! Animations are recorded and played here as well, keyframe by keyframe. A keyframe or a save
+//! that fails is reported in the robot status.
+extern crate alloc;
+
+use crate::config::TELEOP_MIN_SPEED;
+use crate::kinematics::gait_engine::{GaitEngine, MotionError};
+use crate::robot::animations;
+use crate::robot::commands::{ServoCommand, TcpCommand, Velocity};
+use crate::robot::leg::Leg;
+use crate::robot::macros::{self, Op, STOP_MACRO};
+use crate::robot::state::{speeds, update_gait_state};
+use crate::robot::status::update_status;
 use crate::{SERVOCMD_CHANNEL_SIZE, TCPCMD_CHANNEL_SIZE};
+use alloc::vec::Vec;
+use embassy_futures::select::{select, Either};
+use embassy_futures::yield_now;
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Receiver, Sender},
+    signal::Signal,
 };
-use log::{debug, info};
+use embassy_time::Timer;
+use log::{debug, error, info};
+
+/// Latest teleoperation velocity sent by a network client
+pub static TELEOP_VELOCITY: Signal<CriticalSectionRawMutex, Velocity> = Signal::new();
 
 #[embassy_executor::task]
 pub async fn gait_task(
@@ -19,48 +43,256 @@ pub async fn gait_task(
     servo_cmd_sender: Sender<'static, CriticalSectionRawMutex, ServoCommand, SERVOCMD_CHANNEL_SIZE>,
 ) {
     let mut gait = GaitEngine::new(servo_cmd_sender);
-    gait.init_positions().await;
+    if let Err(e) = gait.init_positions().await {
+        error!("[MOTION_TASK] failed to reach the boot position: {e}");
+    }
     debug!("{:?}", gait.config());
 
     loop {
         let stamp = "[MOTION_TASK] received";
-        match tcp_cmd_receiver.receive().await {
+        let cmd = match select(tcp_cmd_receiver.receive(), TELEOP_VELOCITY.wait()).await {
+            Either::First(cmd) => cmd,
+            Either::Second(velocity) => {
+                teleop(&mut gait, velocity, &tcp_cmd_receiver).await;
+                continue;
+            }
+        };
+        // speeds may have been changed over the network since the last command
+        gait.set_speeds(speeds());
+        update_gait_state(|state| state.busy = true);
+
+        let res = match cmd {
             TcpCommand::Test => {
                 info!("{stamp} test");
-                gait.do_test().await;
+                run_macro(&mut gait, "test").await
             }
-            TcpCommand::CloseConnection => {
-                info!("{stamp} close connection");
+            TcpCommand::RunMacro(name) => {
+                info!("{stamp} run {name}");
+                run_macro(&mut gait, &name).await
             }
-            TcpCommand::StepForward(n) => {
-                info!("{stamp} step forward {n}");
-                gait.step_forward(n).await;
+            TcpCommand::PlayAnimation(name) => {
+                info!("{stamp} play {name}");
+                // a stop sent while nothing was running
+                STOP_MACRO.reset();
+                match select(play_animation(&mut gait, &name), STOP_MACRO.wait()).await {
+                    Either::First(res) => res,
+                    Either::Second(()) => {
+                        info!("[MOTION_TASK] animation {name} stopped");
+                        stop_motion(&mut gait).await
+                    }
+                }
             }
-            TcpCommand::StepBackward(n) => {
-                info!("{stamp} step backward {n}");
-                gait.step_backward(n).await;
+            // after the motions queued before, to capture the pose they reach
+            TcpCommand::Keyframe(ms) => {
+                info!("{stamp} keyframe in {ms} ms");
+                let res = animations::add_keyframe(gait.pose(), ms);
+                if let Err(e) = res {
+                    error!("[MOTION_TASK] keyframe not recorded: {e}");
+                }
+                update_status(|status| status.animation = res.err());
+                Ok(())
             }
-            TcpCommand::Wave(n) => {
-                info!("{stamp} wave {n}");
-                gait.wave(n).await;
+            TcpCommand::SaveAnimation => {
+                info!("{stamp} save animation");
+                let res = animations::save().await;
+                match &res {
+                    Ok(name) => info!("[MOTION_TASK] animation {name} saved"),
+                    Err(e) => error!("[MOTION_TASK] animation not saved: {e}"),
+                }
+                update_status(|status| status.animation = res.err());
+                Ok(())
             }
-            TcpCommand::Sit => {
-                info!("{stamp} sit command");
-                gait.sit().await;
-            }
-            TcpCommand::Stand => {
-                info!("{stamp} stand command");
-                gait.stand().await;
-            }
-            TcpCommand::TurnLeft(n) => {
-                info!("{stamp} turn left {n}");
-                gait.turn_left(n).await;
+            cmd => execute(&mut gait, &cmd).await,
+        };
+        update_gait_state(|state| state.busy = false);
+
+        if let Err(e) = res {
+            error!("[MOTION_TASK] command aborted: {e}");
+        }
+    }
+}
+
+/// Execute a motion command, queued or from a macro
+async fn execute(gait: &mut GaitEngine, cmd: &TcpCommand) -> Result<(), MotionError> {
+    let stamp = "[MOTION_TASK] received";
+    match *cmd {
+        TcpCommand::CloseConnection => {
+            info!("{stamp} close connection");
+            Ok(())
+        }
+        TcpCommand::StepForward(n) => {
+            info!("{stamp} step forward {n}");
+            gait.step_forward(n).await
+        }
+        TcpCommand::StepBackward(n) => {
+            info!("{stamp} step backward {n}");
+            gait.step_backward(n).await
+        }
+        TcpCommand::Wave(n) => {
+            info!("{stamp} wave {n}");
+            gait.wave(Leg::FrontRight, n).await
+        }
+        TcpCommand::WaveLeg(leg, n) => {
+            info!("{stamp} wave {leg} {n}");
+            gait.wave(leg, n).await
+        }
+        TcpCommand::Sit => {
+            info!("{stamp} sit command");
+            gait.sit().await
+        }
+        TcpCommand::Stand => {
+            info!("{stamp} stand command");
+            gait.stand().await
+        }
+        TcpCommand::TurnLeft(n) => {
+            info!("{stamp} turn left {n}");
+            gait.turn_left(n).await
+        }
+        TcpCommand::TurnRight(n) => {
+            info!("{stamp} turn right {n}");
+            gait.turn_right(n).await
+        }
+        TcpCommand::Foot(leg, [x, y, z]) => {
+            info!("{stamp} foot {leg} to ({x}, {y}, {z})");
+            gait.set_foot(leg, x, y, z).await
+        }
+        TcpCommand::Bow => {
+            info!("{stamp} bow");
+            gait.bow().await
+        }
+        TcpCommand::PushUps(n) => {
+            info!("{stamp} push-ups {n}");
+            gait.push_ups(n).await
+        }
+        TcpCommand::Shimmy(n) => {
+            info!("{stamp} shimmy {n}");
+            gait.shimmy(n).await
+        }
+        TcpCommand::Hello(leg) => {
+            info!("{stamp} hello with {leg}");
+            gait.hello(leg).await
+        }
+        TcpCommand::Dance => {
+            info!("{stamp} dance");
+            gait.dance().await
+        }
+        TcpCommand::PlayAnimation(ref name) => {
+            info!("{stamp} play {name}");
+            play_animation(gait, name).await
+        }
+        TcpCommand::ClearFault => {
+            info!("{stamp} clear fault");
+            gait.clear_fault();
+            Ok(())
+        }
+        _ => {
+            info!("{stamp} unknown command");
+            Ok(())
+        }
+    }
+}
+
+/// Run the steps of a macro until its end, a fault or [`STOP_MACRO`]
+async fn run_macro(gait: &mut GaitEngine, name: &str) -> Result<(), MotionError> {
+    let program = match macros::find(name).and_then(|steps| macros::compile(&steps)) {
+        Ok(program) => program,
+        Err(e) => {
+            error!("[MOTION_TASK] can't run macro {name}: {e}");
+            return Ok(());
+        }
+    };
+    // a stop sent while no macro was running
+    STOP_MACRO.reset();
+
+    // iterations left of the loops running, `None` for the endless ones
+    let mut loops: Vec<Option<u16>> = Vec::new();
+    let mut next = 0;
+    while let Some(op) = program.get(next) {
+        if STOP_MACRO.signaled() {
+            info!("[MOTION_TASK] macro {name} stopped");
+            break;
+        }
+        next += 1;
+        match op {
+            Op::Command(cmd) => match select(execute(gait, cmd), STOP_MACRO.wait()).await {
+                Either::First(res) => res?,
+                Either::Second(()) => {
+                    info!("[MOTION_TASK] macro {name} stopped");
+                    return stop_motion(gait).await;
+                }
+            },
+            Op::Wait(ms) => {
+                let wait = Timer::after_millis(*ms as u64);
+                if let Either::Second(()) = select(wait, STOP_MACRO.wait()).await {
+                    info!("[MOTION_TASK] macro {name} stopped");
+                    break;
+                }
             }
-            TcpCommand::TurnRight(n) => {
-                info!("{stamp} turn right {n}");
-                gait.turn_right(n).await;
+            Op::Repeat {
+                count: Some(0),
+                end,
+            } => next = end + 1,
+            Op::Repeat { count, .. } => loops.push(*count),
+            Op::End { start } => {
+                // the loop may not hold any motion, let the other tasks run
+                yield_now().await;
+                match loops.last_mut() {
+                    Some(None) => next = start + 1,
+                    Some(Some(left)) if *left > 1 => {
+                        *left -= 1;
+                        next = start + 1;
+                    }
+                    _ => {
+                        loops.pop();
+                    }
+                }
             }
-            _ => info!("{stamp} unknown command"),
         }
     }
+    Ok(())
+}
+
+/// Stop the legs in the middle of the motion given up on, then put every foot on the ground
+async fn stop_motion(gait: &mut GaitEngine) -> Result<(), MotionError> {
+    gait.halt().await?;
+    gait.stand().await
+}
+
+/// Move through the keyframes of an animation until its end or a fault
+async fn play_animation(gait: &mut GaitEngine, name: &str) -> Result<(), MotionError> {
+    let animation = match animations::find(name) {
+        Ok(animation) => animation,
+        Err(e) => {
+            error!("[MOTION_TASK] can't play animation {name}: {e}");
+            return Ok(());
+        }
+    };
+    for keyframe in &animation.keyframes {
+        gait.move_to(&keyframe.pose(), keyframe.duration_ms as u32)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Walk with the latest velocity until it comes back to zero or a command is queued
+async fn teleop(
+    gait: &mut GaitEngine,
+    mut velocity: Velocity,
+    tcp_cmd_receiver: &Receiver<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+) {
+    update_gait_state(|state| state.busy = true);
+    while !velocity.is_stop() && tcp_cmd_receiver.is_empty() {
+        let mut scaled = speeds();
+        scaled.speed_multiple *= velocity.magnitude().max(TELEOP_MIN_SPEED);
+        gait.set_speeds(scaled);
+
+        if let Err(e) = gait.teleop_step(velocity).await {
+            error!("[MOTION_TASK] teleoperation aborted: {e}");
+            break;
+        }
+        if let Some(latest) = TELEOP_VELOCITY.try_take() {
+            velocity = latest;
+        }
+    }
+    update_gait_state(|state| state.busy = false);
 }
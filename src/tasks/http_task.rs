@@ -0,0 +1,507 @@
+//! HTTP/1.1 server task.
+//!
+//! Serves a small REST API next to the line-based TCP server, so browsers, curl or home
+//! automation can drive the robot:
+//! - `POST /cmd`: executes a command written with the TCP grammar (`sf 2`, `aux pan 90`...)
+//! - `GET /state`: pose reached by the gait engine, busy flag and status, as JSON
+//! - `GET /config`: gait speeds as a JSON object
+//! - `PUT /config`: updates some or all of the speeds, applied from the next command on
+//! - `GET /hostname`: the name announced over mDNS, as JSON
+//! - `PUT /hostname`: renames the robot, the body being the new name as plain text
+//! - `GET /mqtt`: the MQTT broker, as JSON
+//! - `PUT /mqtt`: sets the MQTT broker as `host[:port]` in plain text, an empty body disables MQTT
+//! - `GET /`: the web control panel (`src/web/panel.html`), built on the API above
+//! - `GET /ws`: WebSocket for teleoperation and telemetry, see the `websocket` module
+//! - `GET /auth`: whether authentication is required and, if so, a challenge, as JSON
+//! - `POST /auth`: the challenge and its HMAC in hex separated by a space, answered with a token
+//! - `PUT /secret`: sets the shared secret in plain text, an empty body disables authentication
+//!
+//! Once a secret is set, the requests changing anything (every `PUT`, `POST /cmd` but for
+//! `status`) need an `Authorization: Bearer <token>` header, see [`crate::auth`].
+//!
+//! A single request is served per connection, the connection is closed after the response.
+//! Several instances of the task listen on the port, as browsers open parallel connections.
+extern crate alloc;
+
+use crate::auth::{self, SetSecretError};
+use crate::config::{Speeds, AUXCMD_CHANNEL_SIZE, LEG_MOUNTS, TCPCMD_CHANNEL_SIZE};
+use crate::config::{HTTP_BUF_SIZE, HTTP_PORT, HTTP_TIMEOUT_MS};
+use crate::robot::animations::AnimationError;
+use crate::robot::commands::{AuxCommand, TcpCommand};
+use crate::robot::macros::MacroError;
+use crate::robot::state::{gait_state, set_speeds, speeds};
+use crate::robot::status::{robot_status, DriverState, RobotStatus};
+use crate::tasks::mdns_task::{hostname, set_hostname, HostnameError};
+use crate::tasks::mqtt_task::{broker, set_broker, BrokerError};
+use crate::tasks::net_task::{dispatch, Dispatched};
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write as _;
+use embassy_net::{
+    tcp::{Error as TcpError, TcpSocket},
+    IpAddress, IpListenEndpoint, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use log::{error, info, warn};
+
+mod websocket;
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    /// Header lines, without the request line
+    headers: &'a str,
+    body: &'a str,
+}
+
+impl Request<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+    }
+}
+
+/// Single page UI, compiled into the firmware
+const PANEL: &str = include_str!("../web/panel.html");
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Cow<'static, str>,
+}
+
+impl Response {
+    fn json(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: Cow::Owned(body),
+        }
+    }
+
+    fn html(page: &'static str) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: Cow::Borrowed(page),
+        }
+    }
+
+    fn ok(body: String) -> Self {
+        Self::json("200 OK", body)
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self::json(status, format!("{{\"error\":\"{message}\"}}"))
+    }
+}
+
+enum RequestError {
+    /// The client left or didn't send its request in time
+    Disconnected,
+    Invalid(Response),
+}
+
+#[embassy_executor::task(pool_size = 3)]
+pub async fn http_task(
+    stack: Stack<'static>,
+    cmd_sender: Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) {
+    let mut rx_buf = [0u8; HTTP_BUF_SIZE];
+    let mut tx_buf = [0u8; HTTP_BUF_SIZE];
+    let mut request_buf = [0u8; HTTP_BUF_SIZE];
+
+    while !stack.is_link_up() {
+        Timer::after_millis(500).await;
+    }
+
+    if let Some(config) = stack.config_v4() {
+        info!(
+            "HTTP server listening at address {}:{}",
+            config.address, HTTP_PORT
+        );
+    }
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        // a client that stalls must not block the server
+        socket.set_timeout(Some(Duration::from_millis(HTTP_TIMEOUT_MS)));
+
+        if let Err(e) = socket
+            .accept(IpListenEndpoint {
+                port: HTTP_PORT,
+                addr: None,
+            })
+            .await
+        {
+            error!("HTTP accept failed: {:?}", e);
+            Timer::after_millis(500).await; // Backoff delay
+            continue;
+        }
+
+        let response = match read_request(&mut socket, &mut request_buf).await {
+            Ok(request) if request.path == "/ws" => {
+                // the connection stays open until the client leaves
+                websocket::serve(&mut socket, request, &cmd_sender, &aux_sender).await;
+                socket.close();
+                let _ = socket.flush().await;
+                continue;
+            }
+            Ok(request) => {
+                let client = socket.remote_endpoint().map(|endpoint| endpoint.addr);
+                handle_request(request, client, &cmd_sender, &aux_sender).await
+            }
+            Err(RequestError::Invalid(response)) => response,
+            Err(RequestError::Disconnected) => {
+                socket.abort();
+                continue;
+            }
+        };
+
+        if let Err(e) = write_response(&mut socket, response).await {
+            warn!("HTTP write error: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+/// Read the request line, the headers and the body announced by `Content-Length`
+async fn read_request<'a>(
+    socket: &mut TcpSocket<'_>,
+    buf: &'a mut [u8],
+) -> Result<Request<'a>, RequestError> {
+    let mut len = 0;
+    let header_end = loop {
+        if let Some(pos) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if len == buf.len() {
+            return Err(RequestError::Invalid(Response::error(
+                "431 Request Header Fields Too Large",
+                "headers too large",
+            )));
+        }
+        len += read_some(socket, &mut buf[len..]).await?;
+    };
+
+    let content_length = content_length(&buf[..header_end])?;
+    let request_end = header_end.saturating_add(content_length);
+    if request_end > buf.len() {
+        return Err(RequestError::Invalid(Response::error(
+            "413 Payload Too Large",
+            "body too large",
+        )));
+    }
+    while len < request_end {
+        len += read_some(socket, &mut buf[len..request_end]).await?;
+    }
+
+    let (head, body) = buf[..request_end].split_at(header_end);
+    let head = core::str::from_utf8(head).map_err(|_| bad_request())?;
+    let body = core::str::from_utf8(body).map_err(|_| bad_request())?;
+
+    let (request_line, headers) = head.split_once("\r\n").unwrap_or((head, ""));
+    let mut request_line = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(bad_request());
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(bad_request());
+    }
+    // the query string isn't used
+    let path = target.split('?').next().unwrap_or_default();
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn read_some(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<usize, RequestError> {
+    match socket.read(buf).await {
+        Ok(0) => Err(RequestError::Disconnected),
+        Ok(n) => Ok(n),
+        Err(e) => {
+            warn!("HTTP read error: {:?}", e);
+            Err(RequestError::Disconnected)
+        }
+    }
+}
+
+/// Length of the body, 0 without a `Content-Length` header
+fn content_length(head: &[u8]) -> Result<usize, RequestError> {
+    let head = core::str::from_utf8(head).map_err(|_| bad_request())?;
+    for line in head.lines().skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                return value.trim().parse().map_err(|_| bad_request());
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn bad_request() -> RequestError {
+    RequestError::Invalid(Response::error("400 Bad Request", "malformed request"))
+}
+
+async fn handle_request(
+    request: Request<'_>,
+    client: Option<IpAddress>,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> Response {
+    info!("HTTP {} {}", request.method, request.path);
+    let read_only = request.method == "GET"
+        || request.path == "/auth"
+        || (request.path == "/cmd"
+            && TcpCommand::try_from(request.body).is_ok_and(|cmd| auth::read_only(&cmd)));
+    if !read_only && !auth::http_authorized(request.header("authorization")) {
+        return Response::error("401 Unauthorized", "authentication required");
+    }
+
+    match (request.method, request.path) {
+        ("POST", "/cmd") => post_cmd(request.body, cmd_sender, aux_sender).await,
+        ("GET", "/state") => Response::ok(state_json()),
+        ("GET", "/config") => Response::ok(speeds_json(&speeds())),
+        ("PUT", "/config") => put_config(request.body),
+        ("GET", "/hostname") => Response::ok(hostname_json(&hostname())),
+        ("PUT", "/hostname") => put_hostname(request.body).await,
+        ("GET", "/mqtt") => Response::ok(broker_json()),
+        ("PUT", "/mqtt") => put_broker(request.body).await,
+        ("GET", "/auth") => Response::ok(challenge_json()),
+        ("POST", "/auth") => post_auth(request.body, client),
+        ("PUT", "/secret") => put_secret(request.body).await,
+        ("GET", "/" | "/index.html") => Response::html(PANEL),
+        (_, "/cmd" | "/state" | "/config" | "/hostname" | "/mqtt" | "/auth" | "/secret" | "/") => {
+            Response::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+async fn post_cmd(
+    body: &str,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> Response {
+    let Ok(cmd) = TcpCommand::try_from(body) else {
+        return Response::error("400 Bad Request", "unrecognised command");
+    };
+    match dispatch(cmd, cmd_sender, aux_sender).await {
+        Dispatched::Close => {
+            Response::error("400 Bad Request", "close only applies to the TCP port")
+        }
+        Dispatched::Status(status) => {
+            let mut json = String::new();
+            write_status(&mut json, status);
+            Response::ok(json)
+        }
+        Dispatched::Forwarded => Response::json("202 Accepted", String::from("{\"queued\":true}")),
+        Dispatched::Done => Response::ok(String::from("{\"ok\":true}")),
+        Dispatched::Macros(names) => Response::ok(format!("{{\"macros\":{}}}", names_json(&names))),
+        Dispatched::Failed(e @ MacroError::Unknown) => {
+            Response::error("404 Not Found", &format!("{e}"))
+        }
+        Dispatched::Failed(e @ MacroError::Settings(_)) => {
+            Response::error("500 Internal Server Error", &format!("{e}"))
+        }
+        Dispatched::Failed(e) => Response::error("400 Bad Request", &format!("{e}")),
+        Dispatched::Animations(names) => {
+            Response::ok(format!("{{\"animations\":{}}}", names_json(&names)))
+        }
+        Dispatched::AnimationFailed(e @ AnimationError::Unknown) => {
+            Response::error("404 Not Found", &format!("{e}"))
+        }
+        Dispatched::AnimationFailed(e @ AnimationError::Settings(_)) => {
+            Response::error("500 Internal Server Error", &format!("{e}"))
+        }
+        Dispatched::AnimationFailed(e) => Response::error("400 Bad Request", &format!("{e}")),
+    }
+}
+
+/// JSON array of macro or animation names
+pub(crate) fn names_json<const N: usize>(names: &[heapless::String<N>]) -> String {
+    let mut json = String::from("[");
+    for (i, name) in names.iter().enumerate() {
+        let separator = if i > 0 { "," } else { "" };
+        let _ = write!(json, "{separator}\"{name}\"");
+    }
+    json.push(']');
+    json
+}
+
+fn put_config(body: &str) -> Response {
+    match parse_speeds(body, speeds()) {
+        Some(new_speeds) => {
+            set_speeds(new_speeds);
+            Response::ok(speeds_json(&new_speeds))
+        }
+        None => Response::error(
+            "400 Bad Request",
+            "expected a JSON object of positive speeds",
+        ),
+    }
+}
+
+async fn put_hostname(body: &str) -> Response {
+    match set_hostname(body).await {
+        Ok(new) => Response::ok(hostname_json(&new)),
+        Err(e @ HostnameError::Invalid) => Response::error("400 Bad Request", &format!("{e}")),
+        Err(e @ HostnameError::Settings(_)) => {
+            Response::error("500 Internal Server Error", &format!("{e}"))
+        }
+    }
+}
+
+fn hostname_json(hostname: &str) -> String {
+    format!("{{\"hostname\":\"{hostname}\"}}")
+}
+
+async fn put_broker(body: &str) -> Response {
+    match set_broker(body).await {
+        Ok(()) => Response::ok(broker_json()),
+        Err(e @ BrokerError::Invalid) => Response::error("400 Bad Request", &format!("{e}")),
+        Err(e @ BrokerError::Settings(_)) => {
+            Response::error("500 Internal Server Error", &format!("{e}"))
+        }
+    }
+}
+
+fn broker_json() -> String {
+    match broker() {
+        Some(broker) => format!("{{\"broker\":\"{broker}\"}}"),
+        None => String::from("{\"broker\":null}"),
+    }
+}
+
+fn challenge_json() -> String {
+    match auth::secret() {
+        Some(_) => format!(
+            "{{\"required\":true,\"challenge\":\"{}\"}}",
+            auth::http_challenge()
+        ),
+        None => String::from("{\"required\":false}"),
+    }
+}
+
+fn post_auth(body: &str, client: Option<IpAddress>) -> Response {
+    let Some((challenge, response)) = body.trim().split_once(' ') else {
+        return Response::error("400 Bad Request", "expected <challenge> <response>");
+    };
+    match client.and_then(|client| auth::http_login(challenge, response, client)) {
+        Some(token) => Response::ok(format!("{{\"token\":\"{token}\"}}")),
+        None => {
+            warn!("HTTP authentication failed");
+            Response::error("401 Unauthorized", "authentication failed")
+        }
+    }
+}
+
+async fn put_secret(body: &str) -> Response {
+    match auth::set_secret(body).await {
+        Ok(()) => Response::ok(format!("{{\"required\":{}}}", auth::secret().is_some())),
+        Err(e @ SetSecretError::TooLong) => Response::error("400 Bad Request", &format!("{e}")),
+        Err(e @ SetSecretError::Settings(_)) => {
+            Response::error("500 Internal Server Error", &format!("{e}"))
+        }
+    }
+}
+
+/// Update `speeds` with the members of a flat JSON object such as `{"leg_move_speed": 6}`
+fn parse_speeds(body: &str, mut speeds: Speeds) -> Option<Speeds> {
+    let members = body.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+    if members.is_empty() {
+        return Some(speeds);
+    }
+    for member in members.split(',') {
+        let (name, value) = member.split_once(':')?;
+        let name = name.trim().strip_prefix('"')?.strip_suffix('"')?;
+        let value: f32 = value.trim().parse().ok()?;
+        if !value.is_finite() || value <= 0.0 {
+            return None;
+        }
+        *speeds.field_mut(name)? = value;
+    }
+    Some(speeds)
+}
+
+fn state_json() -> String {
+    let mut json = String::from("{");
+    write_state(&mut json);
+    json.push('}');
+    json
+}
+
+/// Members of the state object, shared with the telemetry and MQTT
+pub(crate) fn write_state(json: &mut String) {
+    let state = gait_state();
+    let _ = write!(json, "\"busy\":{},\"pose\":[", state.busy);
+    for (i, [x, y, z]) in state.pose.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let _ = write!(json, "{separator}[{x:.1},{y:.1},{z:.1}]");
+    }
+    // Same positions in the body frame, to draw the robot from above
+    json.push_str("],\"feet\":[");
+    for (i, (mount, [x, y, z])) in LEG_MOUNTS.iter().zip(state.pose).enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let (x, y) = mount.to_body(x, y);
+        let _ = write!(json, "{separator}[{x:.1},{y:.1},{z:.1}]");
+    }
+    json.push_str("],\"status\":");
+    write_status(json, robot_status());
+}
+
+pub(crate) fn write_status(json: &mut String, status: RobotStatus) {
+    let driver = match status.driver {
+        DriverState::Ready => "ready",
+        DriverState::Missing => "missing",
+    };
+    let _ = write!(json, "{{\"driver\":\"{driver}\",\"fault\":");
+    let _ = match status.fault {
+        Some(fault) => write!(json, "\"{fault}\",\"animation\":"),
+        None => write!(json, "null,\"animation\":"),
+    };
+    let _ = match status.animation {
+        Some(e) => write!(json, "\"{e}\"}}"),
+        None => write!(json, "null}}"),
+    };
+}
+
+fn speeds_json(speeds: &Speeds) -> String {
+    let mut json = String::from("{");
+    for (i, (name, value)) in speeds.fields().iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let _ = write!(json, "{separator}\"{name}\":{value}");
+    }
+    json.push('}');
+    json
+}
+
+async fn write_response(socket: &mut TcpSocket<'_>, response: Response) -> Result<(), TcpError> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.flush().await
+}
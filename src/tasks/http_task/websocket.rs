@@ -0,0 +1,376 @@
+//! WebSocket endpoint for teleoperation and telemetry.
+//!
+//! Text messages use the TCP command grammar and go through the same dispatch, `vel <forward>
+//! <turn>` and `foot <leg> <x> <y> <z>` being the ones meant for streaming. `rate <hz>` sets the
+//! telemetry rate of the connection (0 to stop it).
+//!
+//! Telemetry messages carry the gait state, the command queue depth, the status and the
+//! Wi-Fi signal strength. They are sent at the configured rate and on every status change.
+//! Replies are JSON objects told apart by their `type`: `telemetry`, `status`, `auth` or `error`.
+//!
+//! When a shared secret is set, the first message is a `challenge` and commands other than
+//! `status`, `close` and `rate` are refused until the client sent `auth <hmac hex>`.
+use super::{names_json, write_response, write_state, write_status, Request, Response};
+use crate::auth;
+use crate::config::{AUXCMD_CHANNEL_SIZE, TCPCMD_CHANNEL_SIZE};
+use crate::config::{TELEMETRY_HZ, TELEMETRY_MAX_HZ, WS_BUF_SIZE, WS_TIMEOUT_MS};
+use crate::robot::commands::{AuxCommand, TcpCommand, Velocity};
+use crate::robot::status::{status_changed, ROBOT_STATUS};
+use crate::tasks::gait_task::TELEOP_VELOCITY;
+use crate::tasks::net_task::{dispatch, Dispatched};
+use crate::tasks::wifi_task::wifi_rssi;
+use alloc::format;
+use alloc::string::String;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use core::fmt::Write as _;
+use core::future::pending;
+use core::ops::{ControlFlow, Range};
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::{Error as TcpError, TcpSocket};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{Duration, Ticker};
+use embedded_io_async::Write;
+use log::{info, warn};
+use sha1::{Digest, Sha1};
+
+/// Appended to the client key to compute the handshake answer (RFC 6455)
+const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// A complete frame received from the client, unmasked in the receive buffer
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Range<usize>,
+    /// Bytes taken by the frame in the buffer
+    len: usize,
+}
+
+/// State of one WebSocket connection
+struct Session {
+    telemetry: Option<Ticker>,
+    /// The client sent a velocity, the robot must stop when it leaves
+    teleoperating: bool,
+    auth: auth::Session,
+}
+
+/// Answer the upgrade request then exchange messages until the client leaves
+pub(super) async fn serve(
+    socket: &mut TcpSocket<'_>,
+    request: Request<'_>,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) {
+    let is_upgrade = request.method == "GET"
+        && request
+            .header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let Some(key) = request.header("sec-websocket-key").filter(|_| is_upgrade) else {
+        let response = Response::error("400 Bad Request", "expected a WebSocket upgrade");
+        let _ = write_response(socket, response).await;
+        return;
+    };
+
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    if socket.write_all(handshake.as_bytes()).await.is_err() {
+        return;
+    }
+    info!("WebSocket client connected");
+    socket.set_timeout(Some(Duration::from_millis(WS_TIMEOUT_MS)));
+    socket.set_keep_alive(Some(Duration::from_millis(WS_TIMEOUT_MS / 3)));
+
+    let mut session = Session {
+        telemetry: telemetry_ticker(TELEMETRY_HZ),
+        teleoperating: false,
+        auth: auth::Session::new(),
+    };
+    if let Some(challenge) = session.auth.challenge() {
+        let json = format!("{{\"type\":\"challenge\",\"challenge\":\"{challenge}\"}}");
+        if write_frame(socket, OP_TEXT, json.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    let mut status_receiver = ROBOT_STATUS.receiver();
+    if status_receiver.is_none() {
+        warn!("Too many status subscribers, telemetry only sent at its rate");
+    }
+
+    let mut buf = [0u8; WS_BUF_SIZE];
+    let mut len = 0;
+
+    'connection: loop {
+        match select3(
+            socket.read(&mut buf[len..]),
+            next_tick(&mut session.telemetry),
+            status_changed(&mut status_receiver),
+        )
+        .await
+        {
+            Either3::First(Ok(0)) | Either3::First(Err(_)) => break,
+            Either3::First(Ok(n)) => {
+                len += n;
+                // a read may hold several frames, or the start of one
+                loop {
+                    let frame = match parse_frame(&mut buf[..len]) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(code) => {
+                            let _ = close(socket, code).await;
+                            break 'connection;
+                        }
+                    };
+                    let flow = handle_frame(
+                        socket,
+                        &frame,
+                        &buf[frame.payload.clone()],
+                        &mut session,
+                        cmd_sender,
+                        aux_sender,
+                    )
+                    .await;
+                    if flow.is_break() {
+                        break 'connection;
+                    }
+                    buf.copy_within(frame.len..len, 0);
+                    len -= frame.len;
+                }
+            }
+            Either3::Second(()) | Either3::Third(_) => {
+                let telemetry = telemetry_json(cmd_sender.len());
+                if write_frame(socket, OP_TEXT, telemetry.as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    if session.teleoperating {
+        TELEOP_VELOCITY.signal(Velocity::STOP);
+    }
+    info!("WebSocket client disconnected");
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(WS_GUID);
+    STANDARD.encode(sha.finalize())
+}
+
+/// The first frame of `buf`, `None` until it is complete, or the close code to reply with
+fn parse_frame(buf: &mut [u8]) -> Result<Option<Frame>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    // every frame from a client is masked
+    if buf[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (payload_len, mut offset) = match buf[1] & 0x7f {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 => return Err(CLOSE_TOO_BIG),
+        n => (n as usize, 2),
+    };
+    let frame_len = offset + 4 + payload_len;
+    if frame_len > WS_BUF_SIZE {
+        return Err(CLOSE_TOO_BIG);
+    }
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+
+    let mask = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    offset += 4;
+    for (i, byte) in buf[offset..frame_len].iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload: offset..frame_len,
+        len: frame_len,
+    }))
+}
+
+async fn handle_frame(
+    socket: &mut TcpSocket<'_>,
+    frame: &Frame,
+    payload: &[u8],
+    session: &mut Session,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> ControlFlow<()> {
+    // messages are short, fragmented ones aren't supported
+    if !frame.fin || frame.opcode == OP_CONTINUATION {
+        let _ = close(socket, CLOSE_UNSUPPORTED).await;
+        return ControlFlow::Break(());
+    }
+
+    let reply = match frame.opcode {
+        OP_TEXT => {
+            let Ok(text) = core::str::from_utf8(payload) else {
+                let _ = close(socket, CLOSE_INVALID_DATA).await;
+                return ControlFlow::Break(());
+            };
+            let text = text.trim();
+
+            if let Some(rate) = text.strip_prefix("rate ") {
+                match rate.trim().parse::<u32>() {
+                    Ok(hz) => {
+                        session.telemetry = telemetry_ticker(hz.min(TELEMETRY_MAX_HZ));
+                        None
+                    }
+                    Err(_) => Some(error_json("invalid rate")),
+                }
+            } else if let Some(response) = text.strip_prefix("auth ") {
+                let client = socket.remote_endpoint().map(|endpoint| endpoint.addr);
+                if session.auth.authenticate(response, client) {
+                    Some(String::from("{\"type\":\"auth\",\"ok\":true}"))
+                } else {
+                    // the challenge changed, the client needs it for another attempt
+                    Some(format!(
+                        "{{\"type\":\"auth\",\"ok\":false,\"challenge\":\"{}\"}}",
+                        session.auth.challenge().unwrap_or_default()
+                    ))
+                }
+            } else if TcpCommand::try_from(text).is_ok_and(|cmd| !session.auth.allows(&cmd)) {
+                Some(error_json("authentication required"))
+            } else if let Ok(cmd) = TcpCommand::try_from(text) {
+                if matches!(cmd, TcpCommand::Velocity(_)) {
+                    session.teleoperating = true;
+                }
+                match dispatch(cmd, cmd_sender, aux_sender).await {
+                    Dispatched::Close => {
+                        let _ = close(socket, CLOSE_NORMAL).await;
+                        return ControlFlow::Break(());
+                    }
+                    Dispatched::Status(status) => {
+                        let mut json = String::from("{\"type\":\"status\",\"status\":");
+                        write_status(&mut json, status);
+                        json.push('}');
+                        Some(json)
+                    }
+                    Dispatched::Forwarded | Dispatched::Done => None,
+                    Dispatched::Macros(names) => Some(format!(
+                        "{{\"type\":\"macros\",\"macros\":{}}}",
+                        names_json(&names)
+                    )),
+                    Dispatched::Animations(names) => Some(format!(
+                        "{{\"type\":\"animations\",\"animations\":{}}}",
+                        names_json(&names)
+                    )),
+                    Dispatched::Failed(e) => Some(error_json(&format!("{e}"))),
+                    Dispatched::AnimationFailed(e) => Some(error_json(&format!("{e}"))),
+                }
+            } else {
+                warn!("Unrecognised command: {}", text);
+                Some(error_json("unrecognised command"))
+            }
+        }
+        OP_BINARY => {
+            let _ = close(socket, CLOSE_UNSUPPORTED).await;
+            return ControlFlow::Break(());
+        }
+        OP_CLOSE => {
+            let _ = close(socket, CLOSE_NORMAL).await;
+            return ControlFlow::Break(());
+        }
+        OP_PING => {
+            if write_frame(socket, OP_PONG, payload).await.is_err() {
+                return ControlFlow::Break(());
+            }
+            None
+        }
+        _ => None,
+    };
+
+    if let Some(reply) = reply {
+        if write_frame(socket, OP_TEXT, reply.as_bytes())
+            .await
+            .is_err()
+        {
+            return ControlFlow::Break(());
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+fn telemetry_ticker(hz: u32) -> Option<Ticker> {
+    (hz > 0).then(|| Ticker::every(Duration::from_hz(hz as u64)))
+}
+
+async fn next_tick(telemetry: &mut Option<Ticker>) {
+    match telemetry {
+        Some(ticker) => ticker.next().await,
+        None => pending().await,
+    }
+}
+
+fn telemetry_json(queue_depth: usize) -> String {
+    let mut json = String::from("{\"type\":\"telemetry\",");
+    write_state(&mut json);
+    let _ = write!(json, ",\"queue\":{queue_depth},\"rssi\":");
+    let _ = match wifi_rssi() {
+        Some(rssi) => write!(json, "{rssi}}}"),
+        None => write!(json, "null}}"),
+    };
+    json
+}
+
+fn error_json(message: &str) -> String {
+    format!("{{\"type\":\"error\",\"error\":\"{message}\"}}")
+}
+
+async fn close(socket: &mut TcpSocket<'_>, code: u16) -> Result<(), TcpError> {
+    write_frame(socket, OP_CLOSE, &code.to_be_bytes()).await
+}
+
+/// Send an unmasked frame, as servers do
+async fn write_frame(
+    socket: &mut TcpSocket<'_>,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<(), TcpError> {
+    let mut header = [0x80 | opcode, 0, 0, 0];
+    let header_len = match payload.len() {
+        len @ 0..=125 => {
+            header[1] = len as u8;
+            2
+        }
+        len => {
+            header[1] = 126;
+            header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            4
+        }
+    };
+    socket.write_all(&header[..header_len]).await?;
+    socket.write_all(payload).await?;
+    socket.flush().await
+}
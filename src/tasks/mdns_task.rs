@@ -0,0 +1,428 @@
+//! mDNS / DNS-SD responder task.
+//!
+//! Answers the multicast DNS queries (RFC 6762) for `<hostname>.local` and advertises the TCP
+//! command server as a `_spiderbot._tcp` service (RFC 6763), so scripts find the robots of the
+//! LAN without reading their address on the serial monitor. The TXT record of the service gives
+//! the other ports, `http=80` and `udp=1235`.
+//!
+//! The hostname defaults to `spiderbot-<mac>`, the last three bytes of the MAC address in hex.
+//! It is changed over HTTP (`PUT /hostname`) and kept in the settings. The records are announced
+//! twice when the robot joins the network, and again when the hostname changes after the
+//! previous name was withdrawn.
+extern crate alloc;
+
+use crate::config::{HOSTNAME_SIZE, HTTP_PORT, PORT, UDP_PORT};
+use crate::config::{MDNS_ANNOUNCE_MS, MDNS_BUF_SIZE, MDNS_TTL_S};
+use crate::settings::{self, SettingsError};
+use alloc::format;
+use alloc::string::String as NameString;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::{Display, Write as _};
+use core::future::pending;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use esp_hal::efuse::Efuse;
+use heapless::String;
+use log::{debug, error, info, warn};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const SERVICE: &str = "_spiderbot._tcp.local";
+/// Service type enumeration of DNS-SD
+const SERVICES: &str = "_services._dns-sd._udp.local";
+/// Time to live of the answers to a legacy unicast query
+const LEGACY_TTL_S: u32 = 10;
+
+const HEADER_SIZE: usize = 12;
+/// Response with authoritative answers
+const FLAGS_RESPONSE: u16 = 0x8400;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the records owned by this robot alone, replacing the ones cached by the clients
+const CACHE_FLUSH: u16 = 0x8000;
+/// Compression pointers followed in a name, stops a looping packet
+const MAX_POINTERS: usize = 8;
+
+/// Name of the robot on the LAN, without the `.local` domain
+pub type Hostname = String<HOSTNAME_SIZE>;
+
+static HOSTNAME: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Hostname>>> =
+    BlockingMutex::new(RefCell::new(None));
+/// The previous hostname, to withdraw
+static HOSTNAME_CHANGED: Signal<CriticalSectionRawMutex, Hostname> = Signal::new();
+
+#[derive(Debug)]
+pub enum HostnameError {
+    Invalid,
+    Settings(SettingsError),
+}
+
+impl Display for HostnameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostnameError::Invalid => {
+                f.write_str("a hostname is 1 to 63 letters, digits or hyphens")
+            }
+            HostnameError::Settings(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// The stored hostname, `spiderbot-<mac>` if none was set
+pub fn hostname() -> Hostname {
+    if let Some(hostname) = HOSTNAME.lock(|hostname| hostname.borrow().clone()) {
+        return hostname;
+    }
+    let hostname = settings::load().hostname.unwrap_or_else(default_hostname);
+    HOSTNAME.lock(|current| current.replace(Some(hostname.clone())));
+    hostname
+}
+
+fn default_hostname() -> Hostname {
+    let mac = Efuse::mac_address();
+    let mut hostname = Hostname::new();
+    let _ = write!(
+        hostname,
+        "spiderbot-{:02x}{:02x}{:02x}",
+        mac[3], mac[4], mac[5]
+    );
+    hostname
+}
+
+/// Rename the robot, the name is stored and announced right away
+pub async fn set_hostname(name: &str) -> Result<Hostname, HostnameError> {
+    let name = name.trim();
+    if !valid_hostname(name) {
+        return Err(HostnameError::Invalid);
+    }
+    let mut new = Hostname::try_from(name).map_err(|_| HostnameError::Invalid)?;
+    new.make_ascii_lowercase();
+
+    let stored = new.clone();
+    settings::update(|s| s.hostname = Some(stored))
+        .await
+        .map_err(HostnameError::Settings)?;
+    let previous = hostname();
+    HOSTNAME.lock(|current| current.replace(Some(new.clone())));
+    if previous != new {
+        HOSTNAME_CHANGED.signal(previous);
+    }
+    Ok(new)
+}
+
+/// A single DNS label: letters, digits and hyphens, not at either end
+fn valid_hostname(name: &str) -> bool {
+    (1..=HOSTNAME_SIZE).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 2 * MDNS_BUF_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 2 * MDNS_BUF_SIZE];
+    let mut packet = [0u8; MDNS_BUF_SIZE];
+
+    stack.wait_config_up().await;
+    if let Err(e) = stack.join_multicast_group(MDNS_GROUP) {
+        error!("mDNS join failed: {:?}", e);
+        return;
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        error!("mDNS bind failed: {:?}", e);
+        return;
+    }
+
+    let group = IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT);
+    let mut host = hostname();
+    info!("mDNS announcing {host}.local");
+    announce(&socket, stack, &host, MDNS_TTL_S).await;
+    // the announcement is repeated once, in case the first one was lost
+    let mut repeat_announcement = true;
+
+    loop {
+        let repeat = async {
+            if !repeat_announcement {
+                pending::<()>().await;
+            }
+            Timer::after_millis(MDNS_ANNOUNCE_MS).await
+        };
+        match select3(
+            socket.recv_from(&mut packet),
+            HOSTNAME_CHANGED.wait(),
+            repeat,
+        )
+        .await
+        {
+            Either3::First(Ok((len, meta))) => {
+                let Some((answers, additional)) = parse_query(&packet[..len], &host) else {
+                    continue;
+                };
+                if !answers.any() {
+                    continue;
+                }
+                let Some(address) = ipv4_address(stack) else {
+                    continue;
+                };
+                debug!("mDNS query from {}", meta.endpoint);
+                // a legacy resolver sends from another port and waits for a unicast reply
+                let (id, ttl, to) = if meta.endpoint.port == MDNS_PORT {
+                    (0, MDNS_TTL_S, group)
+                } else {
+                    let id = u16::from_be_bytes([packet[0], packet[1]]);
+                    (id, LEGACY_TTL_S, meta.endpoint)
+                };
+                let response = response(id, answers, additional, &host, address, ttl);
+                if let Err(e) = socket.send_to(&response, to).await {
+                    warn!("mDNS send error: {:?}", e);
+                }
+            }
+            Either3::First(Err(e)) => warn!("mDNS receive error: {:?}", e),
+            Either3::Second(previous) => {
+                // a time to live of 0 withdraws the records of the previous name
+                announce(&socket, stack, &previous, 0).await;
+                host = hostname();
+                info!("mDNS announcing {host}.local");
+                announce(&socket, stack, &host, MDNS_TTL_S).await;
+                repeat_announcement = true;
+            }
+            Either3::Third(()) => {
+                announce(&socket, stack, &host, MDNS_TTL_S).await;
+                repeat_announcement = false;
+            }
+        }
+    }
+}
+
+fn ipv4_address(stack: Stack<'_>) -> Option<Ipv4Address> {
+    stack.config_v4().map(|config| config.address.address())
+}
+
+/// Send every record unsolicited
+async fn announce(socket: &UdpSocket<'_>, stack: Stack<'_>, host: &str, ttl: u32) {
+    let Some(address) = ipv4_address(stack) else {
+        return;
+    };
+    let response = response(0, Records::ALL, Records::NONE, host, address, ttl);
+    let group = IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT);
+    if let Err(e) = socket.send_to(&response, group).await {
+        warn!("mDNS announcement error: {:?}", e);
+    }
+}
+
+/// Records of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Records {
+    /// A record of `<hostname>.local`
+    host: bool,
+    /// PTR from the service type to this robot
+    service: bool,
+    /// SRV of this robot, its hostname and command port
+    srv: bool,
+    /// TXT of this robot, its other ports
+    txt: bool,
+    /// PTR from the DNS-SD enumeration to the service type
+    services: bool,
+}
+
+impl Records {
+    const NONE: Self = Self {
+        host: false,
+        service: false,
+        srv: false,
+        txt: false,
+        services: false,
+    };
+    const ALL: Self = Self {
+        host: true,
+        service: true,
+        srv: true,
+        txt: true,
+        services: false,
+    };
+
+    fn any(&self) -> bool {
+        self.count() > 0
+    }
+
+    fn count(&self) -> u16 {
+        [self.host, self.service, self.srv, self.txt, self.services]
+            .iter()
+            .filter(|record| **record)
+            .count() as u16
+    }
+}
+
+/// Records answering a query and the additional ones the client needs next, `None` if the
+/// packet isn't a valid query
+fn parse_query(packet: &[u8], host: &str) -> Option<(Records, Records)> {
+    let header = packet.get(..HEADER_SIZE)?;
+    if header[2] & 0x80 != 0 {
+        return None; // a response, from another responder or this one
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let host_name = format!("{host}.local");
+    let instance = format!("{host}.{SERVICE}");
+
+    let mut answers = Records::NONE;
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, offset)?;
+        let qtype = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        offset = next + 4; // type and class
+        let asks = |rtype: u16| qtype == rtype || qtype == TYPE_ANY;
+
+        if name.eq_ignore_ascii_case(&host_name) {
+            answers.host |= asks(TYPE_A);
+        } else if name.eq_ignore_ascii_case(SERVICE) {
+            answers.service |= asks(TYPE_PTR);
+        } else if name.eq_ignore_ascii_case(&instance) {
+            answers.srv |= asks(TYPE_SRV);
+            answers.txt |= asks(TYPE_TXT);
+        } else if name.eq_ignore_ascii_case(SERVICES) {
+            answers.services |= asks(TYPE_PTR);
+        }
+    }
+    if offset > packet.len() {
+        return None;
+    }
+
+    let resolving = answers.service || answers.srv;
+    let additional = Records {
+        srv: answers.service && !answers.srv,
+        txt: answers.service && !answers.txt,
+        host: resolving && !answers.host,
+        ..Records::NONE
+    };
+    Some((answers, additional))
+}
+
+/// The dotted name at `offset` and the offset following it, compression pointers resolved
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(NameString, usize)> {
+    let mut name = NameString::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(offset + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end = end.or(Some(offset + 2));
+                offset = (len & 0x3f) << 8 | *packet.get(offset + 1)? as usize;
+            }
+            len if len < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(core::str::from_utf8(label).ok()?);
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn response(
+    id: u16,
+    answers: Records,
+    additional: Records,
+    host: &str,
+    address: Ipv4Address,
+    ttl: u32,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(MDNS_BUF_SIZE);
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(&FLAGS_RESPONSE.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes()); // questions
+    data.extend_from_slice(&answers.count().to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes()); // authority records
+    data.extend_from_slice(&additional.count().to_be_bytes());
+    write_records(&mut data, answers, host, address, ttl);
+    write_records(&mut data, additional, host, address, ttl);
+    data
+}
+
+fn write_records(data: &mut Vec<u8>, records: Records, host: &str, address: Ipv4Address, ttl: u32) {
+    let host_name = format!("{host}.local");
+    let instance = format!("{host}.{SERVICE}");
+    let unique = CLASS_IN | CACHE_FLUSH;
+
+    if records.services {
+        write_record(data, SERVICES, TYPE_PTR, CLASS_IN, ttl, |data| {
+            write_name(data, SERVICE)
+        });
+    }
+    if records.service {
+        write_record(data, SERVICE, TYPE_PTR, CLASS_IN, ttl, |data| {
+            write_name(data, &instance)
+        });
+    }
+    if records.srv {
+        write_record(data, &instance, TYPE_SRV, unique, ttl, |data| {
+            data.extend_from_slice(&[0, 0, 0, 0]); // priority and weight
+            data.extend_from_slice(&PORT.to_be_bytes());
+            write_name(data, &host_name);
+        });
+    }
+    if records.txt {
+        write_record(data, &instance, TYPE_TXT, unique, ttl, |data| {
+            for entry in [format!("http={HTTP_PORT}"), format!("udp={UDP_PORT}")] {
+                data.push(entry.len() as u8);
+                data.extend_from_slice(entry.as_bytes());
+            }
+        });
+    }
+    if records.host {
+        write_record(data, &host_name, TYPE_A, unique, ttl, |data| {
+            data.extend_from_slice(&address.octets())
+        });
+    }
+}
+
+fn write_record(
+    data: &mut Vec<u8>,
+    name: &str,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: impl FnOnce(&mut Vec<u8>),
+) {
+    write_name(data, name);
+    data.extend_from_slice(&rtype.to_be_bytes());
+    data.extend_from_slice(&class.to_be_bytes());
+    data.extend_from_slice(&ttl.to_be_bytes());
+    let len_offset = data.len();
+    data.extend_from_slice(&[0, 0]);
+    rdata(data);
+    let len = (data.len() - len_offset - 2) as u16;
+    data[len_offset..len_offset + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Uncompressed name, a label for each dotted part
+fn write_name(data: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+}
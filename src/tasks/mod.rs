@@ -3,9 +3,23 @@
 //! This module contains Embassy async tasks for the robot's runtime, including:
 //! - [`motion_task`]: Handles high-level motion commands and gait execution.
 //! - [`servo_task`]: Drives the servo controller to move legs as commanded.
-//! - [`net_task`]: Manages WiFi, TCP server, and command reception.
+//! - [`net_task`]: Runs the network stack, TCP server, and command reception.
+//! - [`wifi_task`]: Joins the stored Wi-Fi network and applies the credentials provisioned over BLE.
+//! - [`http_task`]: Serves the REST API (commands, state and speeds) over HTTP.
+//! - [`mdns_task`]: Announces `<hostname>.local` and the `_spiderbot._tcp` service over mDNS.
+//! - [`mqtt_task`]: MQTT client taking commands and publishing the state for fleet control.
+//! - [`ota_task`]: Receives signed firmware updates and confirms or rolls back an update at boot.
+//! - [`udp_task`]: Receives joystick velocities over UDP, with a deadman timeout.
+//! - [`ble_task`]: GATT service to drive the robot over Bluetooth LE, without Wi-Fi.
 //!
 //! Tasks are spawned from `main.rs` and communicate via Embassy channels.
+pub mod ble_task;
 pub mod gait_task;
+pub mod http_task;
+pub mod mdns_task;
+pub mod mqtt_task;
 pub mod net_task;
+pub mod ota_task;
 pub mod servo_task;
+pub mod udp_task;
+pub mod wifi_task;
@@ -0,0 +1,518 @@
+//! MQTT 3.1.1 client task.
+//!
+//! Connects to the broker stored in the settings (`PUT /mqtt`) so a fleet of robots can be
+//! driven from a single broker. The robot id is its hostname:
+//! - `spiderbot/<id>/cmd`: commands in the TCP grammar, fed to the same dispatch as `net_task`
+//! - `spiderbot/<id>/state`: JSON events published by the robot, told apart by their `event`
+//!   member: `state` (pose, busy flag and status, on every change), `status` (on a driver or
+//!   fault change, or when asked with `status`), `heartbeat` (uptime and Wi-Fi RSSI, every
+//!   [`MQTT_HEARTBEAT_MS`]) and `offline`, the last will published by the broker when the robot
+//!   drops off
+//!
+//! The broker can't authenticate its clients with the robot: once a secret is set (see
+//! [`crate::auth`]), only the read-only commands such as `status` are accepted on the command
+//! topic.
+//!
+//! Every message is sent and subscribed with QoS 0. The client reconnects every
+//! [`MQTT_RETRY_MS`] while the broker is unreachable.
+extern crate alloc;
+
+use crate::auth;
+use crate::config::{AUXCMD_CHANNEL_SIZE, BROKER_SIZE, TCPCMD_CHANNEL_SIZE};
+use crate::config::{MQTT_BUF_SIZE, MQTT_HEARTBEAT_MS, MQTT_KEEP_ALIVE_S, MQTT_PORT};
+use crate::config::{MQTT_RETRY_MS, MQTT_STATE_PERIOD_MS, MQTT_TIMEOUT_MS};
+use crate::robot::commands::{AuxCommand, TcpCommand};
+use crate::robot::leg::Pose;
+use crate::robot::state::gait_state;
+use crate::robot::status::{robot_status, status_changed, RobotStatus, ROBOT_STATUS};
+use crate::settings::{self, SettingsError};
+use crate::tasks::http_task::{write_state, write_status};
+use crate::tasks::mdns_task::hostname;
+use crate::tasks::net_task::{dispatch, Dispatched};
+use crate::tasks::wifi_task::wifi_rssi;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Write as _};
+use core::ops::Range;
+use embassy_futures::select::{select, select4, Either4};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
+use embassy_sync::signal::Signal;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+use embedded_io_async::Write;
+use log::{info, warn};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82; // reserved flags of SUBSCRIBE are 0b0010
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+const PROTOCOL_LEVEL: u8 = 4; // 3.1.1
+const CLEAN_SESSION: u8 = 0x02;
+const WILL_FLAG: u8 = 0x04;
+const SUBACK_FAILURE: u8 = 0x80;
+const SUBSCRIBE_ID: u16 = 1;
+
+/// The broker changed, the session restarts with the new one
+static BROKER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug)]
+pub enum MqttError {
+    /// The broker name couldn't be resolved
+    Dns,
+    /// The broker couldn't be reached or closed the connection
+    Connection,
+    /// The broker refused the connection, with its return code
+    Refused(u8),
+    SubscriptionRefused,
+    /// The broker didn't answer in time
+    Timeout,
+    /// Malformed or oversized packet from the broker
+    Protocol,
+}
+
+impl Display for MqttError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MqttError::Dns => f.write_str("broker name not resolved"),
+            MqttError::Connection => f.write_str("connection lost"),
+            MqttError::Refused(code) => write!(f, "connection refused, code {code}"),
+            MqttError::SubscriptionRefused => f.write_str("subscription refused"),
+            MqttError::Timeout => f.write_str("no answer from the broker"),
+            MqttError::Protocol => f.write_str("invalid packet"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BrokerError {
+    Invalid,
+    Settings(SettingsError),
+}
+
+impl Display for BrokerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BrokerError::Invalid => f.write_str("expected host[:port], or nothing to disable"),
+            BrokerError::Settings(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// The stored broker, `None` when MQTT is disabled
+pub fn broker() -> Option<heapless::String<BROKER_SIZE>> {
+    settings::load().mqtt_broker
+}
+
+/// Store the broker as `host[:port]` and connect to it, an empty address disables MQTT
+pub async fn set_broker(address: &str) -> Result<(), BrokerError> {
+    let address = address.trim();
+    let broker = if address.is_empty() {
+        None
+    } else {
+        let (host, port) = split_broker(address).ok_or(BrokerError::Invalid)?;
+        if !valid_host(host) || port == 0 {
+            return Err(BrokerError::Invalid);
+        }
+        Some(heapless::String::try_from(address).map_err(|_| BrokerError::Invalid)?)
+    };
+    settings::update(|s| s.mqtt_broker = broker)
+        .await
+        .map_err(BrokerError::Settings)?;
+    BROKER_CHANGED.signal(());
+    Ok(())
+}
+
+/// A host name or an IPv4 address, which also keeps the broker safe to put in JSON
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+}
+
+/// Host and port of `host[:port]`, `None` if the port isn't a number
+fn split_broker(address: &str) -> Option<(&str, u16)> {
+    match address.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None => Some((address, MQTT_PORT)),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: Stack<'static>,
+    cmd_sender: Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) {
+    let mut rx_buf = [0u8; MQTT_BUF_SIZE];
+    let mut tx_buf = [0u8; MQTT_BUF_SIZE];
+
+    stack.wait_config_up().await;
+
+    loop {
+        let Some(broker) = broker() else {
+            info!("No MQTT broker configured");
+            BROKER_CHANGED.wait().await;
+            continue;
+        };
+
+        info!("Connecting to MQTT broker {broker}");
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        match session(stack, &mut socket, &broker, &cmd_sender, &aux_sender).await {
+            // the broker changed, connect to the new one right away
+            Ok(()) => continue,
+            Err(e) => warn!("MQTT broker {broker}: {e}"),
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+
+        select(Timer::after_millis(MQTT_RETRY_MS), BROKER_CHANGED.wait()).await;
+    }
+}
+
+/// Connect, subscribe, then relay commands and events until the connection fails or the broker
+/// changes
+async fn session(
+    stack: Stack<'static>,
+    socket: &mut TcpSocket<'_>,
+    broker: &str,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> Result<(), MqttError> {
+    let endpoint = resolve(stack, broker).await?;
+    let keep_alive = Duration::from_secs(MQTT_KEEP_ALIVE_S as u64);
+    socket.set_timeout(Some(keep_alive * 2));
+    socket.set_keep_alive(Some(keep_alive / 2));
+    socket
+        .connect(endpoint)
+        .await
+        .map_err(|_| MqttError::Connection)?;
+
+    let id = hostname();
+    let cmd_topic = format!("spiderbot/{id}/cmd");
+    let state_topic = format!("spiderbot/{id}/state");
+    let mut reader = PacketReader::new();
+
+    send(socket, &connect_packet(&id, &state_topic)).await?;
+    let connack = reader.expect(socket, CONNACK).await?;
+    match connack.as_slice() {
+        [_, 0] => {}
+        [_, code] => return Err(MqttError::Refused(*code)),
+        _ => return Err(MqttError::Protocol),
+    }
+    send(socket, &subscribe_packet(&cmd_topic)).await?;
+    let suback = reader.expect(socket, SUBACK).await?;
+    if suback.get(2).is_none_or(|code| *code & SUBACK_FAILURE != 0) {
+        return Err(MqttError::SubscriptionRefused);
+    }
+    info!("MQTT connected as {id}, commands on {cmd_topic}");
+
+    let mut status_receiver = ROBOT_STATUS.receiver();
+    if status_receiver.is_none() {
+        warn!("Too many status subscribers, MQTT won't publish the status changes");
+    }
+    let mut ticker = Ticker::every(Duration::from_millis(MQTT_STATE_PERIOD_MS));
+    let mut last_heartbeat = Instant::now();
+    let mut last_state: Option<(Pose, bool)> = None;
+
+    loop {
+        match select4(
+            reader.fill(socket),
+            status_changed(&mut status_receiver),
+            ticker.next(),
+            BROKER_CHANGED.wait(),
+        )
+        .await
+        {
+            Either4::First(result) => {
+                result?;
+                while let Some(packet) = reader.next_packet()? {
+                    if packet.kind & 0xf0 == PUBLISH {
+                        let body = reader.buf[packet.body.clone()].to_vec();
+                        handle_publish(
+                            socket,
+                            packet.kind,
+                            &body,
+                            &state_topic,
+                            cmd_sender,
+                            aux_sender,
+                        )
+                        .await?;
+                    }
+                    // PINGRESP and anything else needs no answer
+                    reader.consume(packet.len);
+                }
+            }
+            Either4::Second(status) => publish_status(socket, &state_topic, status).await?,
+            Either4::Third(()) => {
+                let state = gait_state();
+                if last_state != Some((state.pose, state.busy)) {
+                    last_state = Some((state.pose, state.busy));
+                    let mut json = String::from("{\"event\":\"state\",");
+                    write_state(&mut json);
+                    json.push('}');
+                    send(socket, &publish_packet(&state_topic, json.as_bytes())).await?;
+                }
+                if last_heartbeat.elapsed() >= Duration::from_millis(MQTT_HEARTBEAT_MS) {
+                    last_heartbeat = Instant::now();
+                    let json = heartbeat_json();
+                    send(socket, &publish_packet(&state_topic, json.as_bytes())).await?;
+                    send(socket, &[PINGREQ, 0]).await?;
+                }
+            }
+            Either4::Fourth(()) => {
+                let _ = send(socket, &[DISCONNECT, 0]).await;
+                socket.close();
+                let _ = socket.flush().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn resolve(stack: Stack<'static>, broker: &str) -> Result<IpEndpoint, MqttError> {
+    let (host, port) = split_broker(broker).ok_or(MqttError::Dns)?;
+    let address = match host.parse() {
+        Ok(address) => IpAddress::Ipv4(address),
+        Err(_) => *stack
+            .dns_query(host, DnsQueryType::A)
+            .await
+            .map_err(|_| MqttError::Dns)?
+            .first()
+            .ok_or(MqttError::Dns)?,
+    };
+    Ok(IpEndpoint::new(address, port))
+}
+
+/// Execute a command published on the command topic
+async fn handle_publish(
+    socket: &mut TcpSocket<'_>,
+    kind: u8,
+    body: &[u8],
+    state_topic: &str,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> Result<(), MqttError> {
+    let qos = (kind >> 1) & 0x03;
+    let (_topic, rest) = read_string(body).ok_or(MqttError::Protocol)?;
+    // the subscription is QoS 0, a packet id only comes with a higher QoS
+    let payload = if qos > 0 {
+        rest.get(2..).ok_or(MqttError::Protocol)?
+    } else {
+        rest
+    };
+
+    let text = core::str::from_utf8(payload).unwrap_or_default().trim();
+    let Ok(cmd) = TcpCommand::try_from(text) else {
+        warn!("Unrecognised MQTT command: {}", text);
+        return Ok(());
+    };
+    // the broker can't run a challenge, a secret keeps MQTT to the status
+    if auth::secret().is_some() && !auth::read_only(&cmd) {
+        warn!("MQTT command refused, authentication required: {}", text);
+        return Ok(());
+    }
+    match dispatch(cmd, cmd_sender, aux_sender).await {
+        Dispatched::Status(status) => publish_status(socket, state_topic, status).await,
+        Dispatched::Failed(e) => {
+            warn!("MQTT command failed: {e}");
+            Ok(())
+        }
+        Dispatched::AnimationFailed(e) => {
+            warn!("MQTT command failed: {e}");
+            Ok(())
+        }
+        Dispatched::Close
+        | Dispatched::Forwarded
+        | Dispatched::Done
+        | Dispatched::Macros(_)
+        | Dispatched::Animations(_) => Ok(()),
+    }
+}
+
+async fn publish_status(
+    socket: &mut TcpSocket<'_>,
+    state_topic: &str,
+    status: RobotStatus,
+) -> Result<(), MqttError> {
+    let mut json = String::from("{\"event\":\"status\",\"status\":");
+    write_status(&mut json, status);
+    json.push('}');
+    send(socket, &publish_packet(state_topic, json.as_bytes())).await
+}
+
+fn heartbeat_json() -> String {
+    let mut json = format!(
+        "{{\"event\":\"heartbeat\",\"uptime\":{},\"rssi\":",
+        Instant::now().as_secs()
+    );
+    let _ = match wifi_rssi() {
+        Some(rssi) => write!(json, "{rssi}}}"),
+        None => write!(json, "null}}"),
+    };
+    json
+}
+
+async fn send(socket: &mut TcpSocket<'_>, packet: &[u8]) -> Result<(), MqttError> {
+    socket
+        .write_all(packet)
+        .await
+        .map_err(|_| MqttError::Connection)
+}
+
+/// A complete packet in the receive buffer
+struct Packet {
+    /// Type and flags
+    kind: u8,
+    body: Range<usize>,
+    /// Bytes taken by the packet in the buffer
+    len: usize,
+}
+
+/// Receive buffer holding the packets across reads, so a read can be cancelled
+struct PacketReader {
+    buf: [u8; MQTT_BUF_SIZE],
+    len: usize,
+}
+
+impl PacketReader {
+    fn new() -> Self {
+        Self {
+            buf: [0; MQTT_BUF_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Append what the broker sent, cancel safe
+    async fn fill(&mut self, socket: &mut TcpSocket<'_>) -> Result<(), MqttError> {
+        match socket.read(&mut self.buf[self.len..]).await {
+            Ok(0) | Err(_) => Err(MqttError::Connection),
+            Ok(n) => {
+                self.len += n;
+                Ok(())
+            }
+        }
+    }
+
+    /// The first packet of the buffer, `None` until it is complete
+    fn next_packet(&self) -> Result<Option<Packet>, MqttError> {
+        let buf = &self.buf[..self.len];
+        let Some(&kind) = buf.first() else {
+            return Ok(None);
+        };
+        let mut remaining = 0usize;
+        for i in 0..4 {
+            let Some(&byte) = buf.get(1 + i) else {
+                return Ok(None);
+            };
+            remaining |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                let start = 2 + i;
+                let len = start + remaining;
+                if len > MQTT_BUF_SIZE {
+                    return Err(MqttError::Protocol);
+                }
+                if buf.len() < len {
+                    return Ok(None);
+                }
+                return Ok(Some(Packet {
+                    kind,
+                    body: start..len,
+                    len,
+                }));
+            }
+        }
+        Err(MqttError::Protocol)
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+
+    /// Wait for the packet answering a request, skipping keep alive answers
+    async fn expect(&mut self, socket: &mut TcpSocket<'_>, kind: u8) -> Result<Vec<u8>, MqttError> {
+        with_timeout(Duration::from_millis(MQTT_TIMEOUT_MS), async {
+            loop {
+                if let Some(packet) = self.next_packet()? {
+                    let body = self.buf[packet.body].to_vec();
+                    self.consume(packet.len);
+                    if packet.kind == kind {
+                        return Ok(body);
+                    }
+                    if packet.kind != PINGRESP {
+                        return Err(MqttError::Protocol);
+                    }
+                    continue;
+                }
+                self.fill(socket).await?;
+            }
+        })
+        .await
+        .map_err(|_| MqttError::Timeout)?
+    }
+}
+
+fn connect_packet(client_id: &str, state_topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_string(&mut body, "MQTT");
+    body.push(PROTOCOL_LEVEL);
+    body.push(CLEAN_SESSION | WILL_FLAG);
+    body.extend_from_slice(&MQTT_KEEP_ALIVE_S.to_be_bytes());
+    write_string(&mut body, client_id);
+    write_string(&mut body, state_topic);
+    write_string(&mut body, "{\"event\":\"offline\"}");
+    packet(CONNECT, &body)
+}
+
+fn subscribe_packet(topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&SUBSCRIBE_ID.to_be_bytes());
+    write_string(&mut body, topic);
+    body.push(0); // QoS 0
+    packet(SUBSCRIBE, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_string(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH, &body)
+}
+
+/// Fixed header, with the remaining length as a variable length integer, then the body
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(kind);
+    let mut remaining = body.len();
+    loop {
+        let byte = (remaining & 0x7f) as u8;
+        remaining >>= 7;
+        if remaining == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// UTF-8 string prefixed with its length
+fn write_string(body: &mut Vec<u8>, text: &str) {
+    body.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    body.extend_from_slice(text.as_bytes());
+}
+
+/// The string at the start of `data` and the bytes following it
+fn read_string(data: &[u8]) -> Option<(&str, &[u8])> {
+    let len = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    let text = core::str::from_utf8(data.get(2..2 + len)?).ok()?;
+    Some((text, &data[2 + len..]))
+}
@@ -1,22 +1,47 @@
 //! Networking and TCP command server task.
 //!
-//! Manages WiFi connection, listens for TCP commands, parses them, and forwards
-//! them to the motion task for execution.
+//! Runs the network stack, listens for TCP commands, parses them, and forwards
+//! them to the motion task for execution. Status changes (servo driver lost, movement
+//! faults) are pushed to the connected client as `status: ...` lines.
+//!
+//! When a shared secret is set, the client is greeted with `challenge <hex>` and must answer
+//! `auth <hmac hex>` before any command but `status` and `close` (see [`crate::auth`]).
+//!
+//! Text commands are lines, ending with `\n` or `\r\n`. Programs can speak a binary protocol
+//! on the same port instead, see the `binary` module. Each message is told apart by its first
+//! byte.
 //!
 //! Handles network errors and reconnection logic.
 extern crate alloc;
 
-use crate::config::TCPCMD_CHANNEL_SIZE;
+use crate::auth::Session;
+use crate::config::{AUXCMD_CHANNEL_SIZE, TCPCMD_CHANNEL_SIZE};
 use crate::config::{PORT, RX_BUF_SIZE, TX_BUF_SIZE};
-use crate::robot::commands::TcpCommand;
+use crate::robot::animations::{self, AnimationError, AnimationName};
+use crate::robot::commands::{AuxCommand, TcpCommand};
+use crate::robot::macros::{self, MacroError, MacroName, STOP_MACRO};
+use crate::robot::status::{robot_status, status_changed, RobotStatus, ROBOT_STATUS};
+use crate::tasks::gait_task::TELEOP_VELOCITY;
+use alloc::format;
 use alloc::string::String;
-use core::str::FromStr;
-use embassy_net::{tcp::TcpSocket, IpListenEndpoint, Stack};
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    tcp::{Error as TcpError, TcpSocket},
+    IpListenEndpoint, Stack,
+};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
 use embassy_time::Timer;
-use esp_wifi::wifi::{ClientConfiguration, WifiController, WifiDevice};
+use embedded_io_async::Write;
+use esp_wifi::wifi::WifiDevice;
 use log::{error, info, warn};
 
+mod binary;
+mod framing;
+
+use framing::{Assembler, Message};
+
 #[embassy_executor::task]
 pub async fn runner_task(mut runner: embassy_net::Runner<'static, WifiDevice<'static>>) {
     runner.run().await;
@@ -26,6 +51,7 @@ pub async fn runner_task(mut runner: embassy_net::Runner<'static, WifiDevice<'st
 pub async fn net_task(
     stack: Stack<'static>,
     cmd_sender: Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
 ) {
     let mut rx_buf = [0u8; RX_BUF_SIZE];
     let mut tx_buf = [0u8; TX_BUF_SIZE];
@@ -53,7 +79,7 @@ pub async fn net_task(
         {
             Ok(_) => {
                 info!("Client connected!");
-                handle_connection(&mut socket, &cmd_sender).await;
+                handle_connection(&mut socket, &cmd_sender, &aux_sender).await;
             }
             Err(e) => {
                 error!("Accept failed: {:?}", e);
@@ -67,58 +93,242 @@ pub async fn net_task(
 pub async fn handle_connection(
     socket: &mut TcpSocket<'_>,
     cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
 ) {
-    let mut rx_buf = [0u8; RX_BUF_SIZE];
-    loop {
-        match socket.read(&mut rx_buf).await {
-            Ok(0) => break,
-            Ok(n) => {
-                let received_str = core::str::from_utf8(&rx_buf[..n])
-                    .unwrap_or_default()
-                    .trim();
-                if let Ok(cmd) = TcpCommand::try_from(received_str) {
-                    match cmd {
-                        TcpCommand::CloseConnection => break, // special case
-                        _ => cmd_sender.send(cmd).await,
-                    }
-                } else {
-                    warn!("Unrecognised command: {}", received_str);
-                }
+    let mut assembler = Assembler::new();
+    let mut session = Session::new();
+    // set by the first binary frame, status changes are then sent as frames
+    let mut binary_client = false;
+    if let Some(challenge) = session.challenge() {
+        if write_line(socket, &format!("challenge {challenge}\n"))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    // Relay driver and fault changes to the client as they happen
+    let mut status_receiver = ROBOT_STATUS.receiver();
+    if status_receiver.is_none() {
+        warn!("Too many status subscribers, client won't be notified of faults");
+    }
+
+    'connection: loop {
+        let closed = match select(
+            socket.read(assembler.space()),
+            status_changed(&mut status_receiver),
+        )
+        .await
+        {
+            Either::First(Ok(0)) => true,
+            Either::First(Ok(n)) => {
+                assembler.filled(n);
+                false
             }
-            Err(e) => {
+            Either::First(Err(e)) => {
                 error!("Read error: {:?}", e);
                 break;
             }
+            Either::Second(status) => {
+                let written = if binary_client {
+                    binary::write_status(socket, status).await
+                } else {
+                    write_status(socket, status).await
+                };
+                if written.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        while let Some((message, used)) = assembler.next(closed) {
+            let flow = match message {
+                Message::Line(line) => {
+                    handle_text(socket, line, &mut session, cmd_sender, aux_sender).await
+                }
+                Message::InvalidUtf8 => reply(socket, "error: invalid UTF-8\n").await,
+                Message::TooLong => reply(socket, "error: line too long\n").await,
+                Message::Frame(Ok(request)) => {
+                    binary_client = true;
+                    binary::handle_request(socket, request, &mut session, cmd_sender, aux_sender)
+                        .await
+                }
+                Message::Frame(Err(e)) => {
+                    binary_client = true;
+                    binary::reject(socket, e).await
+                }
+                Message::Unrecoverable(e) => {
+                    let _ = binary::reject(socket, e).await;
+                    ControlFlow::Break(())
+                }
+            };
+            if flow.is_break() {
+                break 'connection;
+            }
+            assembler.consume(used);
+        }
+        if closed {
+            break;
         }
     }
 }
 
-pub async fn configurate_and_start_wifi(wifi_controller: &mut WifiController<'_>) {
-    let ssid = env!("WIFI_SSID");
-    let password = env!("WIFI_PASS");
-    let config = esp_wifi::wifi::Configuration::Client(ClientConfiguration {
-        ssid: String::from_str(ssid).unwrap(),
-        password: String::from_str(password).unwrap(),
-        ..Default::default()
-    });
-
-    info!("Connecting to wifi: {ssid}");
-    wifi_controller
-        .set_configuration(&config)
-        .expect("fail setting configuration of wifi controller");
-
-    wifi_controller
-        .set_power_saving(esp_wifi::config::PowerSaveMode::None)
-        .expect("Fail setting wifi power mode");
-
-    wifi_controller.start().unwrap();
-    wifi_controller
-        .connect_async()
-        .await
-        .inspect_err(|e| error!("An error occured trying to connect to wifi: {e:?}"))
-        .unwrap();
+/// Execute a command of the text grammar, `Break` if the connection must be closed
+async fn handle_text(
+    socket: &mut TcpSocket<'_>,
+    text: &str,
+    session: &mut Session,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> ControlFlow<()> {
+    let text = text.trim();
+    if text.is_empty() {
+        return ControlFlow::Continue(());
+    }
+    let written = if let Some(response) = text.strip_prefix("auth ") {
+        let client = socket.remote_endpoint().map(|endpoint| endpoint.addr);
+        let reply = if session.authenticate(response, client) {
+            String::from("auth: ok\n")
+        } else {
+            // the challenge changed, the client needs it for another attempt
+            let challenge = session.challenge().unwrap_or_default();
+            format!("auth: failed\nchallenge {challenge}\n")
+        };
+        write_line(socket, &reply).await
+    } else if let Ok(cmd) = TcpCommand::try_from(text) {
+        if !session.allows(&cmd) {
+            write_line(socket, "error: authentication required\n").await
+        } else {
+            match dispatch(cmd, cmd_sender, aux_sender).await {
+                Dispatched::Close => return ControlFlow::Break(()), // special case
+                Dispatched::Status(status) => write_status(socket, status).await,
+                Dispatched::Forwarded => Ok(()),
+                Dispatched::Done => write_line(socket, "ok\n").await,
+                Dispatched::Macros(names) => {
+                    write_line(socket, &format!("macros: {}\n", join(&names))).await
+                }
+                Dispatched::Failed(e) => write_line(socket, &format!("error: {e}\n")).await,
+                Dispatched::Animations(names) => {
+                    write_line(socket, &format!("animations: {}\n", join(&names))).await
+                }
+                Dispatched::AnimationFailed(e) => {
+                    write_line(socket, &format!("error: {e}\n")).await
+                }
+            }
+        }
+    } else {
+        warn!("Unrecognised command: {}", text);
+        Ok(())
+    };
+    match written {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
 
-    if let Ok(rssi) = wifi_controller.rssi() {
-        info!("Wifi connected! signal: {}", rssi)
+/// What the connection should do once a command was dispatched
+pub enum Dispatched {
+    /// The client asked to close the connection
+    Close,
+    /// The client asked for the status
+    Status(RobotStatus),
+    /// The command was handed to the task executing it
+    Forwarded,
+    /// The command was executed right away
+    Done,
+    /// The client asked for the names of the macros
+    Macros(Vec<MacroName>),
+    /// The command was rejected
+    Failed(MacroError),
+    /// The client asked for the names of the animations
+    Animations(Vec<AnimationName>),
+    /// The animation command was rejected
+    AnimationFailed(AnimationError),
+}
+
+/// Send a command to the task executing it, shared by every network interface
+pub async fn dispatch(
+    cmd: TcpCommand,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> Dispatched {
+    match cmd {
+        TcpCommand::CloseConnection => return Dispatched::Close,
+        TcpCommand::Status => return Dispatched::Status(robot_status()),
+        // macros are stored from here, the gait task only runs them
+        TcpCommand::DefineMacro(name, steps) => {
+            return done_or_failed(macros::define(&name, &steps).await)
+        }
+        TcpCommand::DeleteMacro(name) => return done_or_failed(macros::delete(&name).await),
+        TcpCommand::ListMacros => return Dispatched::Macros(macros::list()),
+        TcpCommand::RunMacro(ref name) => match macros::find(name) {
+            Ok(_) => cmd_sender.send(cmd).await,
+            Err(e) => return Dispatched::Failed(e),
+        },
+        // the macro runs in the gait task, which doesn't read the queue meanwhile
+        TcpCommand::StopMacro => {
+            STOP_MACRO.signal(());
+            return Dispatched::Done;
+        }
+        // the recording is started from here, the keyframes and the save wait for the motions
+        // queued before them
+        TcpCommand::RecordAnimation(name) => {
+            return match animations::record(&name) {
+                Ok(()) => Dispatched::Done,
+                Err(e) => Dispatched::AnimationFailed(e),
+            }
+        }
+        TcpCommand::Keyframe(_) | TcpCommand::SaveAnimation if !animations::recording() => {
+            return Dispatched::AnimationFailed(AnimationError::NotRecording)
+        }
+        TcpCommand::PlayAnimation(ref name) => match animations::find(name) {
+            Ok(_) => cmd_sender.send(cmd).await,
+            Err(e) => return Dispatched::AnimationFailed(e),
+        },
+        TcpCommand::DeleteAnimation(name) => {
+            return match animations::delete(&name).await {
+                Ok(()) => Dispatched::Done,
+                Err(e) => Dispatched::AnimationFailed(e),
+            }
+        }
+        TcpCommand::ListAnimations => return Dispatched::Animations(animations::list()),
+        // auxiliary servos don't wait for the gait in progress
+        TcpCommand::Aux(servo, angle) => aux_sender.send(AuxCommand::new(servo, angle)).await,
+        // only the latest velocity matters, it must not queue up behind other commands
+        TcpCommand::Velocity(velocity) => TELEOP_VELOCITY.signal(velocity),
+        _ => cmd_sender.send(cmd).await,
+    }
+    Dispatched::Forwarded
+}
+
+fn join<const N: usize>(names: &[heapless::String<N>]) -> String {
+    let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    names.join(", ")
+}
+
+fn done_or_failed(result: Result<(), MacroError>) -> Dispatched {
+    match result {
+        Ok(()) => Dispatched::Done,
+        Err(e) => Dispatched::Failed(e),
     }
 }
+
+async fn write_status(socket: &mut TcpSocket<'_>, status: RobotStatus) -> Result<(), TcpError> {
+    write_line(socket, &format!("status: {status}\n")).await
+}
+
+/// Send an error line, `Break` if the connection is lost
+async fn reply(socket: &mut TcpSocket<'_>, line: &str) -> ControlFlow<()> {
+    match write_line(socket, line).await {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+async fn write_line(socket: &mut TcpSocket<'_>, line: &str) -> Result<(), TcpError> {
+    socket
+        .write_all(line.as_bytes())
+        .await
+        .inspect_err(|e| error!("Write error: {:?}", e))
+}
@@ -0,0 +1,231 @@
+//! Binary command protocol, next to the text grammar on the TCP port.
+//!
+//! Programs driving the robot send typed commands, with floats and poses, and match the
+//! replies to their requests. Every message is a frame:
+//!
+//! | byte | content |
+//! | :--- | :------ |
+//! | 0 | magic, `0xA5`, which can't start a text command |
+//! | 1 | protocol version, 1 |
+//! | 2-3 | payload length, u16 little endian |
+//! | 4.. | payload, a [`Request`] or a [`Response`] encoded with postcard |
+//!
+//! The client numbers its requests, each response carrying the id of its request. Once the
+//! client sent a frame, status changes are pushed as responses with the id 0.
+use super::{dispatch, Dispatched};
+use crate::auth::Session;
+use crate::config::KEYFRAME_MAX_MS;
+use crate::config::{AUXCMD_CHANNEL_SIZE, RX_BUF_SIZE, TCPCMD_CHANNEL_SIZE};
+use crate::hex;
+use crate::robot::animations::{AnimationError, AnimationName};
+use crate::robot::auxiliary::AUX_MAX_ANGLE;
+use crate::robot::commands::{AuxCommand, TcpCommand, Velocity};
+use crate::robot::leg::Leg;
+use crate::robot::macros::{MacroError, MacroName};
+use crate::robot::status::RobotStatus;
+use core::ops::ControlFlow;
+use embassy_net::tcp::{Error as TcpError, TcpSocket};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embedded_io_async::Write;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+pub const MAGIC: u8 = 0xA5;
+pub const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4;
+/// Id of the responses the client didn't ask for
+const NOTIFICATION_ID: u32 = 0;
+
+#[derive(Deserialize)]
+pub struct Request {
+    pub id: u32,
+    pub body: RequestBody,
+}
+
+#[derive(Deserialize)]
+pub enum RequestBody {
+    /// Protocol version and authentication challenge
+    Hello,
+    /// HMAC-SHA256 of the challenge, see [`crate::auth`]
+    Auth([u8; 32]),
+    Command(TcpCommand),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response<'a> {
+    pub id: u32,
+    pub body: ResponseBody<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum ResponseBody<'a> {
+    Hello {
+        version: u8,
+        /// Challenge to answer with [`RequestBody::Auth`], `None` if authenticated already
+        challenge: Option<&'a str>,
+    },
+    Auth {
+        ok: bool,
+    },
+    /// The command was handed to the task executing it
+    Queued,
+    Status(RobotStatus),
+    Error(ProtocolError),
+    /// The command was executed right away
+    Done,
+    Macros(&'a [MacroName]),
+    /// The command was rejected
+    Failed(MacroError),
+    Animations(&'a [AnimationName]),
+    /// The animation command was rejected
+    AnimationFailed(AnimationError),
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ProtocolError {
+    /// The payload isn't a valid request
+    Malformed,
+    /// The frame uses another version of the protocol
+    Version,
+    /// The frame doesn't fit in the receive buffer, the connection is closed
+    TooLarge,
+    /// The command needs an authenticated session
+    AuthenticationRequired,
+}
+
+/// The first frame of `buf` and the bytes it takes, `None` until it is complete
+///
+/// An invalid frame is returned as the error to answer. A frame too large to ever fit in the
+/// buffer is an `Err`, the stream can't be followed any longer.
+pub fn parse_frame(
+    buf: &[u8],
+) -> Result<Option<(Result<Request, ProtocolError>, usize)>, ProtocolError> {
+    let &[MAGIC, version, len_lo, len_hi, ..] = buf else {
+        return Ok(None);
+    };
+    let frame_len = HEADER_SIZE + u16::from_le_bytes([len_lo, len_hi]) as usize;
+    if frame_len > RX_BUF_SIZE {
+        return Err(ProtocolError::TooLarge);
+    }
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+    if version != VERSION {
+        return Ok(Some((Err(ProtocolError::Version), frame_len)));
+    }
+    let request = match postcard::take_from_bytes::<Request>(&buf[HEADER_SIZE..frame_len]) {
+        Ok((request, [])) => Ok(request),
+        _ => Err(ProtocolError::Malformed),
+    };
+    Ok(Some((request, frame_len)))
+}
+
+/// Answer a request, `Break` if the connection must be closed
+pub async fn handle_request(
+    socket: &mut TcpSocket<'_>,
+    request: Request,
+    session: &mut Session,
+    cmd_sender: &Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+    aux_sender: &Sender<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
+) -> ControlFlow<()> {
+    let challenge;
+    let names;
+    let animations;
+    let body = match request.body {
+        RequestBody::Hello => {
+            challenge = session.challenge();
+            ResponseBody::Hello {
+                version: VERSION,
+                challenge: challenge.as_deref(),
+            }
+        }
+        RequestBody::Auth(response) => {
+            let client = socket.remote_endpoint().map(|endpoint| endpoint.addr);
+            ResponseBody::Auth {
+                ok: session.authenticate(&hex::encode(&response), client),
+            }
+        }
+        RequestBody::Command(cmd) => match validate(cmd) {
+            None => ResponseBody::Error(ProtocolError::Malformed),
+            Some(cmd) if !session.allows(&cmd) => {
+                ResponseBody::Error(ProtocolError::AuthenticationRequired)
+            }
+            Some(cmd) => match dispatch(cmd, cmd_sender, aux_sender).await {
+                Dispatched::Close => return ControlFlow::Break(()),
+                Dispatched::Status(status) => ResponseBody::Status(status),
+                Dispatched::Forwarded => ResponseBody::Queued,
+                Dispatched::Done => ResponseBody::Done,
+                Dispatched::Macros(list) => {
+                    names = list;
+                    ResponseBody::Macros(&names)
+                }
+                Dispatched::Failed(e) => ResponseBody::Failed(e),
+                Dispatched::Animations(list) => {
+                    animations = list;
+                    ResponseBody::Animations(&animations)
+                }
+                Dispatched::AnimationFailed(e) => ResponseBody::AnimationFailed(e),
+            },
+        },
+    };
+
+    match write_response(socket, request.id, body).await {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+/// Apply the checks of the text grammar, which decoding a command bypasses
+fn validate(cmd: TcpCommand) -> Option<TcpCommand> {
+    match cmd {
+        TcpCommand::Velocity(v) => (v.forward.is_finite() && v.turn.is_finite())
+            .then(|| TcpCommand::Velocity(Velocity::new(v.forward, v.turn))),
+        TcpCommand::Foot(leg, position) => position
+            .iter()
+            .all(|v| v.is_finite())
+            .then_some(TcpCommand::Foot(leg, position)),
+        TcpCommand::Aux(servo, angle) => {
+            (angle <= AUX_MAX_ANGLE).then_some(TcpCommand::Aux(servo, angle))
+        }
+        TcpCommand::Keyframe(ms) => Some(TcpCommand::Keyframe(ms.min(KEYFRAME_MAX_MS))),
+        TcpCommand::Hello(leg @ (Leg::FrontLeft | Leg::FrontRight)) => Some(TcpCommand::Hello(leg)),
+        TcpCommand::Hello(_) => None,
+        cmd => Some(cmd),
+    }
+}
+
+/// Answer a frame that couldn't be read, with the id 0 as its own is unknown
+pub async fn reject(socket: &mut TcpSocket<'_>, e: ProtocolError) -> ControlFlow<()> {
+    warn!("Invalid binary frame: {:?}", e);
+    match write_response(socket, NOTIFICATION_ID, ResponseBody::Error(e)).await {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+pub async fn write_status(socket: &mut TcpSocket<'_>, status: RobotStatus) -> Result<(), TcpError> {
+    write_response(socket, NOTIFICATION_ID, ResponseBody::Status(status)).await
+}
+
+async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    id: u32,
+    body: ResponseBody<'_>,
+) -> Result<(), TcpError> {
+    let mut frame = [0u8; RX_BUF_SIZE];
+    let payload_len = match postcard::to_slice(&Response { id, body }, &mut frame[HEADER_SIZE..]) {
+        Ok(payload) => payload.len(),
+        Err(e) => {
+            // responses are a few bytes, a larger one is a bug
+            error!("Couldn't encode a binary response: {:?}", e);
+            return Ok(());
+        }
+    };
+    frame[0] = MAGIC;
+    frame[1] = VERSION;
+    frame[2..HEADER_SIZE].copy_from_slice(&(payload_len as u16).to_le_bytes());
+    socket
+        .write_all(&frame[..HEADER_SIZE + payload_len])
+        .await
+        .inspect_err(|e| error!("Write error: {:?}", e))
+}
@@ -0,0 +1,105 @@
+//! Splitting of the TCP stream into messages.
+//!
+//! TCP segments don't follow the messages: a read may hold several commands, or part of one.
+//! Bytes are kept in a bounded buffer until they make a complete message, a text line ending
+//! with `\n` (or `\r\n`) or a binary frame. The same commands come out whether they are typed
+//! in telnet, piped to netcat or sent by a script.
+use super::binary::{self, ProtocolError, Request};
+use crate::config::RX_BUF_SIZE;
+
+pub enum Message<'a> {
+    /// A line of text, without its line ending
+    Line(&'a str),
+    /// A line that isn't valid UTF-8
+    InvalidUtf8,
+    /// A line longer than the buffer, dropped up to its end
+    TooLong,
+    Frame(Result<Request, ProtocolError>),
+    /// A binary frame too large for the buffer, the stream can't be followed any longer
+    Unrecoverable(ProtocolError),
+}
+
+pub struct Assembler {
+    buf: [u8; RX_BUF_SIZE],
+    len: usize,
+    /// The end of an overlong line is dropped when it arrives
+    discarding: bool,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; RX_BUF_SIZE],
+            len: 0,
+            discarding: false,
+        }
+    }
+
+    /// Free part of the buffer, to read into
+    pub fn space(&mut self) -> &mut [u8] {
+        &mut self.buf[self.len..]
+    }
+
+    /// Account for `n` bytes read into [`Self::space`]
+    pub fn filled(&mut self, n: usize) {
+        self.len += n;
+    }
+
+    /// The next complete message and the bytes to [`Self::consume`] once it is handled,
+    /// `None` until more bytes arrive. When the client `closed` its side, a last line without
+    /// line ending is complete.
+    pub fn next(&mut self, closed: bool) -> Option<(Message<'_>, usize)> {
+        if self.discarding {
+            match self.buf[..self.len].iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    self.consume(end + 1);
+                    self.discarding = false;
+                }
+                None => {
+                    self.len = 0;
+                    return None;
+                }
+            }
+        }
+        if self.len == 0 {
+            return None;
+        }
+
+        if self.buf[0] == binary::MAGIC {
+            return match binary::parse_frame(&self.buf[..self.len]) {
+                Ok(None) => None,
+                Ok(Some((request, used))) => Some((Message::Frame(request), used)),
+                Err(e) => Some((Message::Unrecoverable(e), self.len)),
+            };
+        }
+
+        let (line, used) = match self.buf[..self.len].iter().position(|&byte| byte == b'\n') {
+            Some(end) => (&self.buf[..end], end + 1),
+            None if closed => (&self.buf[..self.len], self.len),
+            None if self.len == RX_BUF_SIZE => {
+                self.len = 0;
+                self.discarding = true;
+                return Some((Message::TooLong, 0));
+            }
+            None => return None,
+        };
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let message = match core::str::from_utf8(line) {
+            Ok(line) => Message::Line(line),
+            Err(_) => Message::InvalidUtf8,
+        };
+        Some((message, used))
+    }
+
+    /// Drop the first `used` bytes, those of a message handled
+    pub fn consume(&mut self, used: usize) {
+        self.buf.copy_within(used..self.len, 0);
+        self.len -= used;
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,193 @@
+//! OTA update task.
+//!
+//! Receives firmware images on TCP port [`OTA_PORT`]: the client sends a header line
+//! `<size> <sha256> <signature>\n`, the checksum and the Ed25519 signature of the image in hex,
+//! then the image itself (`espflash save-image`). The robot answers `ok` and reboots into the
+//! new firmware, or `error: <reason>` and keeps running the current one.
+//!
+//! At boot the task also watches over an update on trial: the new firmware is confirmed once
+//! the servo task is alive, and rolled back if it isn't within [`OTA_TRIAL_MS`]. The RTC
+//! watchdog runs during the trial so a hang or a panic ends in a reset, the next boot then
+//! rolls back.
+extern crate alloc;
+
+use crate::config::{OTA_BUF_SIZE, OTA_PORT, OTA_TIMEOUT_MS, OTA_TRIAL_MS, OTA_WATCHDOG_MS};
+use crate::hex;
+use crate::ota::{self, BootCheck, OtaError, Update};
+use alloc::format;
+use core::fmt::Display;
+use embassy_futures::join::join;
+use embassy_net::{tcp::TcpSocket, IpListenEndpoint, Stack};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::Write;
+use esp_hal::rtc_cntl::{Rtc, RwdtStage};
+use log::{error, info, warn};
+
+/// Signalled by the servo task once it drives the legs, confirming an update
+pub static SERVO_ALIVE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Longest header line
+const HEADER_SIZE: usize = 256;
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug)]
+enum ReceiveError {
+    /// Malformed header line
+    Header,
+    Disconnected,
+    Ota(OtaError),
+}
+
+impl Display for ReceiveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReceiveError::Header => f.write_str("expected <size> <sha256> <signature>"),
+            ReceiveError::Disconnected => f.write_str("image incomplete"),
+            ReceiveError::Ota(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<OtaError> for ReceiveError {
+    fn from(e: OtaError) -> Self {
+        ReceiveError::Ota(e)
+    }
+}
+
+#[embassy_executor::task]
+pub async fn ota_task(stack: Stack<'static>, mut rtc: Rtc<'static>) {
+    join(boot_trial(&mut rtc), serve(stack)).await;
+}
+
+/// Confirm or roll back the firmware if it is an update on trial
+async fn boot_trial(rtc: &mut Rtc<'static>) {
+    match ota::check_boot() {
+        Ok(BootCheck::Confirmed) => {}
+        Ok(BootCheck::FailedTrial) => {
+            error!("[OTA] the update failed its trial");
+            roll_back();
+        }
+        Ok(BootCheck::Trial) => {
+            info!("[OTA] update on trial, waiting for the servo task");
+            rtc.rwdt.set_timeout(
+                RwdtStage::Stage0,
+                esp_hal::time::Duration::from_millis(OTA_WATCHDOG_MS),
+            );
+            rtc.rwdt.enable();
+            match with_timeout(Duration::from_millis(OTA_TRIAL_MS), SERVO_ALIVE.wait()).await {
+                Ok(()) => {
+                    rtc.rwdt.disable();
+                    match ota::confirm() {
+                        Ok(()) => info!("[OTA] update confirmed"),
+                        Err(e) => error!("[OTA] fail confirming the update: {e}"),
+                    }
+                }
+                Err(_) => {
+                    error!("[OTA] servo task not alive after {OTA_TRIAL_MS} ms");
+                    roll_back();
+                }
+            }
+        }
+        Err(OtaError::Unsupported) => warn!("[OTA] no OTA partitions, updates disabled"),
+        Err(e) => error!("[OTA] {e}"),
+    }
+}
+
+fn roll_back() {
+    match ota::roll_back() {
+        Ok(()) => info!("[OTA] rolling back to the previous firmware"),
+        Err(e) => error!("[OTA] fail rolling back: {e}"),
+    }
+    esp_hal::system::software_reset();
+}
+
+async fn serve(stack: Stack<'static>) {
+    let mut rx_buf = [0u8; OTA_BUF_SIZE];
+    let mut tx_buf = [0u8; 64];
+
+    while !stack.is_link_up() {
+        Timer::after_millis(500).await;
+    }
+    info!("[OTA] listening on port {}", OTA_PORT);
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        socket.set_timeout(Some(Duration::from_millis(OTA_TIMEOUT_MS)));
+        if let Err(e) = socket
+            .accept(IpListenEndpoint {
+                port: OTA_PORT,
+                addr: None,
+            })
+            .await
+        {
+            error!("[OTA] accept failed: {:?}", e);
+            Timer::after_millis(500).await; // Backoff delay
+            continue;
+        }
+
+        info!("[OTA] receiving an update");
+        let result = receive(&mut socket).await;
+        let _ = match &result {
+            Ok(()) => socket.write_all(b"ok\n").await,
+            Err(e) => {
+                error!("[OTA] update rejected: {e}");
+                let reply = format!("error: {e}\n");
+                socket.write_all(reply.as_bytes()).await
+            }
+        };
+        socket.close();
+        let _ = socket.flush().await;
+
+        if result.is_ok() {
+            info!("[OTA] rebooting into the update");
+            Timer::after_millis(500).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+/// Write the image to the inactive slot and select it for the next boot
+async fn receive(socket: &mut TcpSocket<'_>) -> Result<(), ReceiveError> {
+    let mut buf = [0u8; HEADER_SIZE];
+    let mut len = 0;
+    let header_end = loop {
+        if let Some(pos) = buf[..len].iter().position(|byte| *byte == b'\n') {
+            break pos;
+        }
+        if len == buf.len() {
+            return Err(ReceiveError::Header);
+        }
+        len += read_some(socket, &mut buf[len..]).await?;
+    };
+
+    let header = core::str::from_utf8(&buf[..header_end]).map_err(|_| ReceiveError::Header)?;
+    let mut fields = header.split_whitespace();
+    let (Some(size), Some(checksum), Some(signature), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(ReceiveError::Header);
+    };
+    let size: u32 = size.parse().map_err(|_| ReceiveError::Header)?;
+    let checksum: [u8; 32] = hex::decode(checksum).ok_or(ReceiveError::Header)?;
+    let signature: [u8; 64] = hex::decode(signature).ok_or(ReceiveError::Header)?;
+
+    let mut update = Update::begin(size, &signature)?;
+    // the first read may hold the start of the image
+    update.write(&buf[header_end + 1..len])?;
+    let mut chunk = [0u8; CHUNK_SIZE];
+    while update.remaining() > 0 {
+        let max = chunk.len().min(update.remaining() as usize);
+        let n = read_some(socket, &mut chunk[..max]).await?;
+        update.write(&chunk[..n])?;
+    }
+    update.finish(&checksum)?;
+    Ok(())
+}
+
+async fn read_some(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<usize, ReceiveError> {
+    match socket.read(buf).await {
+        Ok(0) | Err(_) => Err(ReceiveError::Disconnected),
+        Ok(n) => Ok(n),
+    }
+}
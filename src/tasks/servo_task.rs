@@ -1,80 +1,380 @@
 //! Servo control task for Spiderbot.
 //!
-//! Receives joint angle commands and drives the servo controller hardware to
-//! move the robot's legs accordingly.
+//! Receives joint angle commands and drives the servo controllers hardware to
+//! move the robot's legs accordingly. Auxiliary servos (head, gripper) are set as soon as
+//! their command arrives, independently of the leg movements.
 //!
-//! Handles servo timing and error reporting.
+//! The task checks for a newer target on every tick, so a command can be preempted
+//! mid-movement: the legs then blend from wherever they are toward the newest target.
+//!
+//! I2C writes are retried, then the I2C controller is reset. If a PCA9685 still doesn't answer
+//! the task keeps running without it: every movement needing it is rejected until the driver
+//! comes back. A missing leg driver is looked for again every [`DRIVER_RETRY_MS`], the optional
+//! auxiliary one only when an `aux` command needs it.
+//!
+//! The gait engine only sends reachable targets, still the servo angles are clamped to the
+//! limits of the robot description before being written.
 extern crate alloc;
 
+use crate::board::{i2c_config, PCA_ADDRESSES, PCA_COUNT, PCA_PRESCALE};
+use crate::config::{
+    ServoChannel, AUXCMD_CHANNEL_SIZE, AUX_SERVO_MAP, DRIVER_RETRY_MS, I2C_RETRIES, SERVO_MAP,
+};
 use crate::kinematics::{
-    conversion::{cartesian_to_polar, polar_to_servo},
+    conversion::{angle_to_ticks, cartesian_to_polar, polar_to_servo},
     gait_engine::MOVEMENT_COMPLETED,
 };
-use crate::robot::commands::ServoCommand;
+use crate::robot::commands::{AuxCommand, ServoCommand};
+use crate::robot::joint::Joint;
+use crate::robot::leg::{Leg, Pose, LEG_COUNT};
+use crate::robot::status::{update_status, DriverState};
+use crate::tasks::ota_task::SERVO_ALIVE;
 use crate::SERVOCMD_CHANNEL_SIZE;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Receiver};
-use embassy_time::{Duration, Ticker};
+use core::cell::Cell;
+use core::fmt::Display;
+use core::future::pending;
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    channel::Receiver,
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Ticker, Timer};
 use esp_hal::{i2c::master::I2c, Async};
-use log::debug;
-use pwm_pca9685::Pca9685;
+use log::{debug, error, info, warn};
+use micromath::F32Ext;
+use pwm_pca9685::{Address, Pca9685};
+use serde::Serialize;
+
+/// Period of the position updates, the servos move by their speed at each one
+pub const UPDATE_PERIOD_MS: u64 = 20;
+
+/// I2C bus shared by the PCA9685
+pub type I2cBus = Mutex<CriticalSectionRawMutex, I2c<'static, Async>>;
+pub type ServoDriver = Pca9685<I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>>;
+
+/// Last position written to the servos, used by the gait engine to resync after a fault.
+/// `None` until the first write.
+pub static SERVO_POSITION: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Pose>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Failures of the servo backend, reported through [`MOVEMENT_COMPLETED`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ServoError {
+    /// The PCA9685 isn't configured, the task runs without servo driver
+    NoDriver,
+    /// Writing the servos failed even after resetting the I2C controller
+    Bus,
+}
+
+impl Display for ServoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ServoError::NoDriver => f.write_str("no servo driver"),
+            ServoError::Bus => f.write_str("servo driver unreachable on the I2C bus"),
+        }
+    }
+}
+
+/// The PCA9685 sharing the I2C bus, indexed like `board::PCA_ADDRESSES`
+pub struct ServoDrivers {
+    bus: &'static I2cBus,
+    pwms: [ServoDriver; PCA_COUNT],
+    ready: [bool; PCA_COUNT],
+    /// Joints held at their limits, warned about when they get there
+    clamped: [[bool; 3]; LEG_COUNT],
+}
+
+impl ServoDrivers {
+    pub fn new(bus: &'static I2cBus) -> Self {
+        let pwms = core::array::from_fn(|i| {
+            Pca9685::new(I2cDevice::new(bus), Address::from(PCA_ADDRESSES[i]))
+                .expect("invalid PCA9685 address")
+        });
+
+        Self {
+            bus,
+            pwms,
+            ready: [false; PCA_COUNT],
+            clamped: [[false; 3]; LEG_COUNT],
+        }
+    }
+
+    /// Configure the drivers that are not ready yet for servo pulses
+    async fn init(&mut self) {
+        for (i, pwm) in self.pwms.iter_mut().enumerate() {
+            if self.ready[i] {
+                continue;
+            }
+            let res = match pwm.set_prescale(PCA_PRESCALE).await {
+                Ok(_) => pwm.enable().await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(_) => {
+                    info!("[SERVO_TASK] servo driver {i} ready");
+                    self.ready[i] = true;
+                }
+                Err(e) => debug!("[SERVO_TASK] Fail configurating pca driver {i}: {e:?}"),
+            }
+        }
+        self.publish();
+    }
+
+    /// Reprogram the I2C controller and have every driver configured again.
+    ///
+    /// Only the ESP32 side is reset: no clock pulse is sent on the bus, so a PCA9685 holding
+    /// SDA low stays stuck until it is power cycled.
+    async fn reset_controller(&mut self) {
+        if let Err(e) = self.bus.lock().await.apply_config(&i2c_config()) {
+            error!("[SERVO_TASK] Fail reconfiguring the I2C bus: {e:?}");
+        }
+        self.ready = [false; PCA_COUNT];
+    }
+
+    /// Whether every driver the legs are wired to is usable
+    fn legs_ready(&self) -> bool {
+        SERVO_MAP
+            .iter()
+            .flatten()
+            .all(|joint| self.ready[joint.servo.driver])
+    }
+
+    fn publish(&self) {
+        let driver = if self.legs_ready() {
+            DriverState::Ready
+        } else {
+            DriverState::Missing
+        };
+        update_status(|status| status.driver = driver);
+    }
 
-const UPDATE_PERIOD_MS: u64 = 20;
+    /// Write every leg, resetting the I2C controller once if the drivers stop answering
+    async fn write_legs(&mut self, pos: &Pose) -> Result<(), ServoError> {
+        if self.try_write_legs(pos).await.is_ok() {
+            return Ok(());
+        }
+
+        warn!("[SERVO_TASK] I2C writes keep failing, resetting the I2C controller");
+        self.reset_controller().await;
+        self.init().await;
+        let res = self.try_write_legs(pos).await;
+        if res.is_err() {
+            error!("[SERVO_TASK] servo driver lost, running without it");
+            self.publish();
+        }
+        res
+    }
+
+    async fn try_write_legs(&mut self, pos: &Pose) -> Result<(), ServoError> {
+        for leg in 0..LEG_COUNT {
+            let (alpha, beta, gamma) = cartesian_to_polar(pos[leg][0], pos[leg][1], pos[leg][2]);
+            let angles = polar_to_servo(leg.into(), alpha, beta, gamma);
+            for joint in 0..3 {
+                let servo = &SERVO_MAP[leg][joint];
+                let angle = servo.clamp(angles[joint]);
+                // also true for NaN, out of reach
+                let clamped = angle != angles[joint];
+                if clamped && !self.clamped[leg][joint] {
+                    warn!(
+                        "[SERVO_TASK] {} {} past its limits, clamped",
+                        Leg::from(leg),
+                        Joint::from(joint)
+                    );
+                }
+                self.clamped[leg][joint] = clamped;
+                if angle.is_nan() {
+                    // the servo keeps its last angle
+                    continue;
+                }
+                self.set_angle(servo.servo, angle).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_aux(&mut self, cmd: AuxCommand) {
+        let servo = AUX_SERVO_MAP[cmd.servo as usize];
+        // The auxiliary driver is optional, only looked for when a command needs it
+        if !self.ready[servo.driver] {
+            self.init().await;
+        }
+        if let Err(e) = self.set_angle(servo, cmd.angle as f32).await {
+            error!("[SERVO_TASK] Fail setting the {}: {e}", cmd.servo);
+        }
+    }
+
+    async fn set_angle(&mut self, servo: ServoChannel, angle: f32) -> Result<(), ServoError> {
+        if !self.ready[servo.driver] {
+            return Err(ServoError::NoDriver);
+        }
+
+        let tick = angle_to_ticks(angle);
+        for attempt in 1..=I2C_RETRIES {
+            match self.pwms[servo.driver]
+                .set_channel_on_off(servo.channel, 0, tick)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!(
+                    "[SERVO_TASK] I2C write to driver {} failed ({attempt}/{I2C_RETRIES}): {e:?}",
+                    servo.driver
+                ),
+            }
+        }
+        self.ready[servo.driver] = false;
+        Err(ServoError::Bus)
+    }
+}
 
 #[embassy_executor::task]
 pub async fn servo_task(
-    mut pwm: Pca9685<I2c<'static, Async>>,
+    mut drivers: ServoDrivers,
     receiver: Receiver<'static, CriticalSectionRawMutex, ServoCommand, SERVOCMD_CHANNEL_SIZE>,
+    aux_receiver: Receiver<'static, CriticalSectionRawMutex, AuxCommand, AUXCMD_CHANNEL_SIZE>,
 ) {
-    pwm.set_prescale(121)
-        .await
-        .expect("Fail configurating pca driver"); //prescale=(25,000,000 / 4096×50) −1
-    pwm.enable().await.expect("Fail enabling the pca driver");
+    drivers.init().await;
+    if !drivers.legs_ready() {
+        error!("[SERVO_TASK] no servo driver for the legs, running without it");
+    }
     let mut ticker = Ticker::every(Duration::from_millis(UPDATE_PERIOD_MS));
 
-    let mut cmd = receiver.receive().await;
+    // The first command is the only one trusted for the current position: afterwards the
+    // servo task is the one knowing where the legs really are.
+    let mut state = receiver.receive().await;
+    info!("[SERVO_TASK] alive");
+    SERVO_ALIVE.signal(());
+    let mut moving = true;
     loop {
-        update_position(&mut cmd, &mut pwm).await;
-        if let Ok(new_cmd) = receiver.try_receive() {
-            cmd = new_cmd;
+        while let Ok(cmd) = aux_receiver.try_receive() {
+            drivers.set_aux(cmd).await;
+        }
+        // Only the newest target matters, older ones still queued are superseded
+        while let Ok(cmd) = receiver.try_receive() {
+            debug!("[SERVO_TASK] Command preempted by a newer one");
+            blend_into(&mut state, &cmd);
+            moving = true;
         }
-        debug!("[SERVO_TASK] Received a command!");
-        ticker.next().await;
-    }
-}
-
-pub async fn update_position(cmd: &mut ServoCommand, pwm: &mut Pca9685<I2c<'static, Async>>) {
-    let mut ticker = Ticker::every(Duration::from_millis(UPDATE_PERIOD_MS));
 
-    loop {
-        for leg in 0..4 {
-            for pos in 0..3 {
-                let diff = (cmd.current_pos[leg][pos] - cmd.expected_pos[leg][pos]).abs();
-                let speed = cmd.temp_speed[leg][pos].abs();
+        if moving {
+            // The step only counts once written, a failed one leaves the state as it was
+            let mut next = state;
+            advance_position(&mut next);
+            let res = if drivers.legs_ready() {
+                drivers.write_legs(&next.current_pos).await
+            } else {
+                Err(ServoError::NoDriver)
+            };
 
-                if diff >= speed {
-                    cmd.current_pos[leg][pos] += cmd.temp_speed[leg][pos];
+            if let Err(e) = res {
+                reject_movement(&mut state, e);
+                moving = false;
+            } else {
+                state = next;
+                SERVO_POSITION.lock(|pos| pos.set(Some(state.current_pos)));
+                if movement_is_done(&state) {
+                    MOVEMENT_COMPLETED.signal(Ok(()));
+                    moving = false;
                 } else {
-                    cmd.current_pos[leg][pos] = cmd.expected_pos[leg][pos];
+                    ticker.next().await;
+                    continue;
                 }
             }
-            let (alpha, beta, gamma) = cartesian_to_polar(
-                cmd.current_pos[leg][0],
-                cmd.current_pos[leg][1],
-                cmd.current_pos[leg][2],
-            );
-            polar_to_servo(pwm, leg.into(), alpha, beta, gamma).await;
         }
-        if movement_is_done(&cmd) {
-            break;
+
+        // Idle: wait for something to do, trying to get the leg drivers back meanwhile
+        let legs_ready = drivers.legs_ready();
+        let retry = async {
+            if legs_ready {
+                pending::<()>().await;
+            }
+            Timer::after_millis(DRIVER_RETRY_MS).await;
+        };
+        match select3(receiver.receive(), aux_receiver.receive(), retry).await {
+            Either3::First(cmd) => {
+                debug!("[SERVO_TASK] Received a command!");
+                blend_into(&mut state, &cmd);
+                moving = true;
+                ticker.reset();
+            }
+            Either3::Second(cmd) => drivers.set_aux(cmd).await,
+            Either3::Third(_) => {
+                drivers.reset_controller().await;
+                drivers.init().await;
+            }
+        }
+    }
+}
+
+/// Give up on the movement in progress and report why to the gait engine, the legs staying at
+/// the last position written
+fn reject_movement(state: &mut ServoCommand, error: ServoError) {
+    state.expected_pos = state.current_pos;
+    MOVEMENT_COMPLETED.signal(Err(error));
+}
+
+/// Retarget the movement in progress toward the expected position of `cmd`.
+///
+/// The speed magnitude requested for each leg is kept, but its direction is recomputed from
+/// the position the leg actually reached so the foot travels in a straight line to the new
+/// target instead of finishing the previous movement first.
+fn blend_into(state: &mut ServoCommand, cmd: &ServoCommand) {
+    let fallback_speed = (0..LEG_COUNT)
+        .map(|leg| norm(&cmd.temp_speed[leg]))
+        .filter(|speed| speed.is_finite())
+        .fold(0.0, f32::max);
+
+    for leg in 0..LEG_COUNT {
+        let mut delta = [0.0; 3];
+        for pos in 0..3 {
+            delta[pos] = cmd.expected_pos[leg][pos] - state.current_pos[leg][pos];
+        }
+        let distance = norm(&delta);
+
+        // set_site yields NaN speeds for the legs that don't move
+        let mut speed = norm(&cmd.temp_speed[leg]);
+        if !speed.is_finite() || speed == 0.0 {
+            speed = fallback_speed;
         }
 
-        ticker.next().await;
+        for pos in 0..3 {
+            state.expected_pos[leg][pos] = cmd.expected_pos[leg][pos];
+            state.temp_speed[leg][pos] = if distance > 0.0 && speed > 0.0 {
+                delta[pos] / distance * speed
+            } else {
+                0.0
+            };
+        }
+        // Nothing can bring this leg to its target, jump there rather than stalling
+        if speed == 0.0 {
+            state.current_pos[leg] = state.expected_pos[leg];
+        }
+    }
+}
+
+/// Advance every leg by one tick toward its expected position.
+fn advance_position(state: &mut ServoCommand) {
+    for leg in 0..LEG_COUNT {
+        for pos in 0..3 {
+            let diff = (state.current_pos[leg][pos] - state.expected_pos[leg][pos]).abs();
+            let speed = state.temp_speed[leg][pos].abs();
+
+            if diff >= speed {
+                state.current_pos[leg][pos] += state.temp_speed[leg][pos];
+            } else {
+                state.current_pos[leg][pos] = state.expected_pos[leg][pos];
+            }
+        }
     }
-    MOVEMENT_COMPLETED.signal(());
+}
+
+fn norm(v: &[f32; 3]) -> f32 {
+    (v[0].powi(2) + v[1].powi(2) + v[2].powi(2)).sqrt()
 }
 
 pub fn movement_is_done(cmd: &ServoCommand) -> bool {
-    for i in 0..4 {
+    for i in 0..LEG_COUNT {
         if !leg_movement_is_done(cmd, i) {
             return false;
         }
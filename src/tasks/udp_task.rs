@@ -0,0 +1,144 @@
+//! UDP joystick task.
+//!
+//! Receives velocity setpoints in a compact binary packet, free of the head-of-line blocking
+//! of TCP. Every packet is 6 bytes:
+//!
+//! | byte | content |
+//! | :--- | :------ |
+//! | 0 | magic, `b'S'` |
+//! | 1 | protocol version, 1 |
+//! | 2-3 | sequence number, u16 big endian, incremented by the client for each packet |
+//! | 4 | forward velocity, i8 from -100 to 100 |
+//! | 5 | turn velocity (left positive), i8 from -100 to 100 |
+//!
+//! Packets older than the last accepted one are dropped. A single joystick drives the robot
+//! at a time: packets from another address are ignored until the current one goes silent. If
+//! the driving joystick sends no accepted packet within [`UDP_DEADMAN_MS`] the robot stops
+//! walking and stands, whatever else reaches the port meanwhile.
+//!
+//! When a shared secret is set, only the addresses that recently authenticated on the TCP,
+//! HTTP or WebSocket interfaces may drive the robot (see [`crate::auth`]).
+use crate::auth;
+use crate::config::{TCPCMD_CHANNEL_SIZE, UDP_DEADMAN_MS, UDP_PORT};
+use crate::robot::commands::{TcpCommand, Velocity};
+use crate::tasks::gait_task::TELEOP_VELOCITY;
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender};
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use log::{debug, error, info, warn};
+
+const MAGIC: u8 = b'S';
+const VERSION: u8 = 1;
+const PACKET_SIZE: usize = 6;
+
+struct Setpoint {
+    seq: u16,
+    velocity: Velocity,
+}
+
+impl Setpoint {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let &[MAGIC, VERSION, seq_hi, seq_lo, forward, turn] = packet else {
+            return None;
+        };
+        let axis = |value: u8| (value as i8) as f32 / 100.0;
+        Some(Self {
+            seq: u16::from_be_bytes([seq_hi, seq_lo]),
+            velocity: Velocity::new(axis(forward), axis(turn)),
+        })
+    }
+}
+
+/// The joystick currently driving the robot
+struct Driver {
+    endpoint: IpEndpoint,
+    last_seq: u16,
+    /// Pushed back by each setpoint accepted from `endpoint`, and only by those
+    deadline: Instant,
+}
+
+#[embassy_executor::task]
+pub async fn udp_task(
+    stack: Stack<'static>,
+    cmd_sender: Sender<'static, CriticalSectionRawMutex, TcpCommand, TCPCMD_CHANNEL_SIZE>,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 4 * PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buf = [0u8; 0];
+    let mut packet = [0u8; PACKET_SIZE + 1]; // one more byte to spot oversized packets
+
+    while !stack.is_link_up() {
+        Timer::after_millis(500).await;
+    }
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if let Err(e) = socket.bind(UDP_PORT) {
+        error!("UDP bind failed: {:?}", e);
+        return;
+    }
+    info!("UDP joystick listening on port {}", UDP_PORT);
+
+    let mut driver: Option<Driver> = None;
+    loop {
+        let received = match &driver {
+            // the deadman only runs while a joystick drives the robot
+            Some(current) => with_deadline(current.deadline, socket.recv_from(&mut packet))
+                .await
+                .ok(),
+            None => Some(socket.recv_from(&mut packet).await),
+        };
+
+        let (len, meta) = match received {
+            Some(Ok(received)) => received,
+            Some(Err(e)) => {
+                warn!("UDP receive error: {:?}", e);
+                continue;
+            }
+            None => {
+                warn!("UDP joystick silent, stopping");
+                driver = None;
+                TELEOP_VELOCITY.signal(Velocity::STOP);
+                if cmd_sender.try_send(TcpCommand::Stand).is_err() {
+                    warn!("Command queue full, couldn't stand after the joystick timeout");
+                }
+                continue;
+            }
+        };
+
+        let Some(setpoint) = Setpoint::parse(&packet[..len]) else {
+            debug!("Invalid UDP packet from {}", meta.endpoint);
+            continue;
+        };
+        if !auth::trusted(meta.endpoint.addr) {
+            debug!("Ignoring unauthenticated joystick {}", meta.endpoint);
+            continue;
+        }
+        match &driver {
+            Some(current) if current.endpoint != meta.endpoint => {
+                debug!(
+                    "Ignoring joystick {}, driven by {}",
+                    meta.endpoint, current.endpoint
+                );
+                continue;
+            }
+            // sequence numbers wrap around, older is less than half the range behind
+            Some(current) if (setpoint.seq.wrapping_sub(current.last_seq) as i16) <= 0 => {
+                debug!("Dropping stale packet {}", setpoint.seq);
+                continue;
+            }
+            Some(_) => {}
+            None => info!("UDP joystick {} connected", meta.endpoint),
+        }
+
+        driver = Some(Driver {
+            endpoint: meta.endpoint,
+            last_seq: setpoint.seq,
+            deadline: Instant::now() + Duration::from_millis(UDP_DEADMAN_MS),
+        });
+        TELEOP_VELOCITY.signal(setpoint.velocity);
+    }
+}
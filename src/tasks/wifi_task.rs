@@ -0,0 +1,175 @@
+//! Wi-Fi station task.
+//!
+//! Joins the network stored in the settings, then keeps the connection up and measures the
+//! signal strength for the telemetry. Without stored credentials the robot stays off Wi-Fi
+//! until they are provisioned over Bluetooth (see `ble_task`): new credentials are tried first
+//! and only stored once the robot managed to connect with them, a wrong password brings the
+//! robot back to the network it was on.
+extern crate alloc;
+
+use crate::config::{RSSI_PERIOD_MS, WIFI_CONNECT_TIMEOUT_MS, WIFI_RETRY_MS};
+use crate::settings::{self, WifiCredentials};
+use alloc::string::String;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicI32, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Timer};
+use esp_wifi::config::PowerSaveMode;
+use esp_wifi::wifi::{ClientConfiguration, Configuration, WifiController, WifiError};
+use log::{info, warn};
+
+/// Credentials to try, sent by the provisioning service
+pub static PROVISIONING: Signal<CriticalSectionRawMutex, WifiCredentials> = Signal::new();
+/// Outcome of the last provisioning, reported to the provisioning service
+pub static PROVISIONING_RESULT: Signal<CriticalSectionRawMutex, ProvisioningState> = Signal::new();
+
+/// Last signal strength of the access point (dBm), 0 until measured
+static WIFI_RSSI: AtomicI32 = AtomicI32::new(0);
+
+/// Progress of a provisioning, the value of the BLE result characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvisioningState {
+    Idle = 0,
+    Connecting = 1,
+    /// Connected and the credentials are stored
+    Connected = 2,
+    /// The robot couldn't connect with the credentials, they were dropped
+    Failed = 3,
+    /// Connected but the credentials couldn't be stored, they are lost on reboot
+    NotStored = 4,
+}
+
+#[derive(Debug)]
+enum ConnectError {
+    Wifi(WifiError),
+    Timeout,
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConnectError::Wifi(e) => write!(f, "{e:?}"),
+            ConnectError::Timeout => f.write_str("no answer from the access point"),
+        }
+    }
+}
+
+impl From<WifiError> for ConnectError {
+    fn from(e: WifiError) -> Self {
+        ConnectError::Wifi(e)
+    }
+}
+
+#[embassy_executor::task]
+pub async fn wifi_task(mut controller: WifiController<'static>) {
+    let mut credentials = settings::load().wifi;
+    let mut connected = match &credentials {
+        Some(wifi) => connect(&mut controller, wifi).await,
+        None => {
+            info!("No Wi-Fi credentials, waiting for provisioning over Bluetooth");
+            false
+        }
+    };
+
+    loop {
+        let period = if connected {
+            RSSI_PERIOD_MS
+        } else {
+            WIFI_RETRY_MS
+        };
+        match select(PROVISIONING.wait(), Timer::after_millis(period)).await {
+            Either::First(provisioned) => {
+                PROVISIONING_RESULT.signal(ProvisioningState::Connecting);
+                if connect(&mut controller, &provisioned).await {
+                    connected = true;
+                    let stored = settings::update(|s| s.wifi = Some(provisioned.clone())).await;
+                    credentials = Some(provisioned);
+                    PROVISIONING_RESULT.signal(match stored {
+                        Ok(()) => ProvisioningState::Connected,
+                        Err(e) => {
+                            warn!("Fail storing the Wi-Fi credentials: {e}");
+                            ProvisioningState::NotStored
+                        }
+                    });
+                } else {
+                    PROVISIONING_RESULT.signal(ProvisioningState::Failed);
+                    // back to the previous network
+                    connected = match &credentials {
+                        Some(wifi) => connect(&mut controller, wifi).await,
+                        None => false,
+                    };
+                }
+            }
+            Either::Second(()) => {
+                if connected {
+                    if controller.is_connected().unwrap_or(false) {
+                        update_rssi(&mut controller);
+                    } else {
+                        warn!("Wifi connection lost");
+                        WIFI_RSSI.store(0, Ordering::Relaxed);
+                        connected = false;
+                    }
+                } else if let Some(wifi) = &credentials {
+                    connected = connect(&mut controller, wifi).await;
+                }
+            }
+        }
+    }
+}
+
+/// Join a network in station mode, leaving the current one if any
+async fn connect(controller: &mut WifiController<'static>, wifi: &WifiCredentials) -> bool {
+    info!("Connecting to wifi: {}", wifi.ssid);
+    match try_connect(controller, wifi).await {
+        Ok(()) => {
+            update_rssi(controller);
+            info!("Wifi connected! signal: {:?}", wifi_rssi());
+            true
+        }
+        Err(e) => {
+            warn!("An error occured trying to connect to wifi: {e}");
+            false
+        }
+    }
+}
+
+async fn try_connect(
+    controller: &mut WifiController<'static>,
+    wifi: &WifiCredentials,
+) -> Result<(), ConnectError> {
+    if controller.is_connected().unwrap_or(false) {
+        controller.disconnect_async().await?;
+    }
+    controller.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: String::from(wifi.ssid.as_str()),
+        password: String::from(wifi.password.as_str()),
+        ..Default::default()
+    }))?;
+    if !controller.is_started()? {
+        controller.set_power_saving(PowerSaveMode::None)?;
+        controller.start_async().await?;
+    }
+    with_timeout(
+        Duration::from_millis(WIFI_CONNECT_TIMEOUT_MS),
+        controller.connect_async(),
+    )
+    .await
+    .map_err(|_| ConnectError::Timeout)??;
+    Ok(())
+}
+
+/// Measure the signal strength, reported by [`wifi_rssi`]
+fn update_rssi(controller: &mut WifiController<'_>) {
+    if let Ok(rssi) = controller.rssi() {
+        WIFI_RSSI.store(rssi, Ordering::Relaxed);
+    }
+}
+
+/// Last signal strength measured (dBm), `None` while disconnected
+pub fn wifi_rssi() -> Option<i32> {
+    match WIFI_RSSI.load(Ordering::Relaxed) {
+        0 => None,
+        rssi => Some(rssi),
+    }
+}